@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Principal};
+use ic_cdk::api::is_controller;
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::{
+        get_memory, impl_cbor_storable, Memory, DEVICE_ENVIRONMENTS_MEMORY_ID,
+        ENVIRONMENTS_ACL_MEMORY_ID,
+    },
+    rdf::GenericError,
+    wot::{DeviceUrl, EnvironmentUid},
+};
+
+/// What a principal can do on a device or on all the devices of an environment.
+///
+/// Each role includes the ones before it.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Serialize, Deserialize,
+)]
+pub enum Role {
+    /// Can see the devices and their commands.
+    Viewer,
+    /// Can send commands to the devices and manage the ones it sent.
+    Operator,
+    /// Can manage all the commands and grant roles to other principals.
+    Admin,
+}
+
+/// The roles granted in an environment by its owner and admins.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct EnvironmentAcl {
+    /// The principal that claimed the environment, which is always an admin.
+    pub owner: Principal,
+    /// The roles on all the devices of the environment.
+    pub roles: BTreeMap<Principal, Role>,
+    /// The roles on single devices, which add to the environment ones.
+    pub device_roles: BTreeMap<DeviceUrl, BTreeMap<Principal, Role>>,
+}
+
+impl_cbor_storable!(EnvironmentAcl);
+
+impl EnvironmentAcl {
+    fn get_role(&self, principal: &Principal, device_url: Option<&DeviceUrl>) -> Option<Role> {
+        if self.owner == *principal {
+            return Some(Role::Admin);
+        }
+
+        let environment_role = self.roles.get(principal).cloned();
+        let device_role = device_url
+            .and_then(|device_url| self.device_roles.get(device_url))
+            .and_then(|roles| roles.get(principal).cloned());
+
+        environment_role.max(device_role)
+    }
+}
+
+/// Who can do what on the environments and their devices.
+///
+/// An environment that hasn't been claimed has no owner:
+/// anyone can view it and every authenticated principal can operate it, as before the ACL existed.
+/// Controllers of the canister are admins of all the environments.
+pub struct AccessControl {
+    environments: StableBTreeMap<EnvironmentUid, EnvironmentAcl, Memory>,
+    /// The environment of each device, recorded when the devices of the environment are fetched.
+    device_environments: StableBTreeMap<DeviceUrl, EnvironmentUid, Memory>,
+}
+
+impl AccessControl {
+    pub fn init() -> Self {
+        Self {
+            environments: StableBTreeMap::init(get_memory(ENVIRONMENTS_ACL_MEMORY_ID)),
+            device_environments: StableBTreeMap::init(get_memory(DEVICE_ENVIRONMENTS_MEMORY_ID)),
+        }
+    }
+
+    pub fn set_device_environment(
+        &mut self,
+        device_url: DeviceUrl,
+        environment_uid: EnvironmentUid,
+    ) {
+        self.device_environments.insert(device_url, environment_uid);
+    }
+
+    pub fn get_device_environment(&self, device_url: &DeviceUrl) -> Option<EnvironmentUid> {
+        self.device_environments.get(device_url)
+    }
+
+    pub fn get_environment_acl(&self, environment_uid: &EnvironmentUid) -> Option<EnvironmentAcl> {
+        self.environments.get(environment_uid)
+    }
+
+    /// Returns the role of the principal on the whole environment.
+    pub fn get_environment_role(
+        &self,
+        principal: &Principal,
+        environment_uid: &EnvironmentUid,
+    ) -> Option<Role> {
+        self.get_role(principal, environment_uid, None)
+    }
+
+    /// Returns the role of the principal on the device, which is none if the environment of the device is unknown.
+    pub fn get_device_role(&self, principal: &Principal, device_url: &DeviceUrl) -> Option<Role> {
+        match self.device_environments.get(device_url) {
+            Some(environment_uid) => self.get_role(principal, &environment_uid, Some(device_url)),
+            None => is_controller(principal).then_some(Role::Admin),
+        }
+    }
+
+    /// Returns the role of the principal on the environment, or on a device of it if given.
+    pub fn get_role(
+        &self,
+        principal: &Principal,
+        environment_uid: &EnvironmentUid,
+        device_url: Option<&DeviceUrl>,
+    ) -> Option<Role> {
+        if is_controller(principal) {
+            return Some(Role::Admin);
+        }
+
+        match self.environments.get(environment_uid) {
+            Some(acl) => acl.get_role(principal, device_url),
+            None if *principal == Principal::anonymous() => Some(Role::Viewer),
+            None => Some(Role::Operator),
+        }
+    }
+
+    /// Makes the principal the owner of the environment, if nobody claimed it yet.
+    pub fn claim_environment(
+        &mut self,
+        environment_uid: EnvironmentUid,
+        owner: Principal,
+    ) -> Result<(), GenericError> {
+        if self.environments.contains_key(&environment_uid) {
+            return Err(format!("Environment {environment_uid} is already claimed"));
+        }
+
+        self.environments.insert(
+            environment_uid,
+            EnvironmentAcl {
+                owner,
+                roles: BTreeMap::new(),
+                device_roles: BTreeMap::new(),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Grants the role to the principal on the whole environment, or on a single device of it.
+    /// Passing no role revokes the one granted before.
+    pub fn set_role(
+        &mut self,
+        environment_uid: &EnvironmentUid,
+        device_url: Option<DeviceUrl>,
+        principal: Principal,
+        role: Option<Role>,
+    ) -> Result<(), GenericError> {
+        let mut acl = self
+            .environments
+            .get(environment_uid)
+            .ok_or_else(|| format!("Environment {environment_uid} is not claimed"))?;
+
+        if acl.owner == principal {
+            return Err("The role of the owner can't be changed".to_string());
+        }
+
+        let roles = match device_url {
+            Some(device_url) => {
+                if self.device_environments.get(&device_url).as_ref() != Some(environment_uid) {
+                    return Err(format!(
+                        "Device {device_url} is not in environment {environment_uid}"
+                    ));
+                }
+                acl.device_roles.entry(device_url).or_default()
+            }
+            None => &mut acl.roles,
+        };
+        match role {
+            Some(role) => roles.insert(principal, role),
+            None => roles.remove(&principal),
+        };
+        acl.device_roles.retain(|_, roles| !roles.is_empty());
+
+        self.environments.insert(environment_uid.clone(), acl);
+
+        Ok(())
+    }
+}
@@ -1,18 +1,43 @@
-use std::collections::BTreeMap;
+use std::{
+    cell::RefCell, cmp::Reverse, collections::BTreeMap, ops::Bound, str::FromStr, time::Duration,
+};
 
 use candid::{CandidType, Nat, Principal};
 use ic_cdk::api::{
     management_canister::http_request::{
         http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
     },
-    print, time,
+    time, trap,
 };
-use serde::{Deserialize, Serialize};
+use ic_cdk_timers::{clear_timer, set_timer, TimerId};
+use ic_stable_structures::StableBTreeMap;
+use serde::{Deserialize, Deserializer, Serialize};
 
-use crate::{wot::DeviceUrl, STATE};
+use crate::{
+    cron::CronSchedule,
+    get_device_request_headers,
+    memory::{
+        get_memory, impl_cbor_storable, Memory, COMMANDS_BY_DEVICE_MEMORY_ID,
+        COMMANDS_BY_SENDER_MEMORY_ID, FINISHED_BY_TIMESTAMP_MEMORY_ID, FINISHED_COMMANDS_MEMORY_ID,
+        QUEUED_COMMANDS_MEMORY_ID, RUNNING_COMMANDS_MEMORY_ID, SCHEDULED_BY_SENDER_MEMORY_ID,
+        SCHEDULED_BY_TIMESTAMP_MEMORY_ID, SCHEDULED_COMMANDS_MEMORY_ID,
+    },
+    rdf::GenericError,
+    utils::log,
+    wot::{DeviceUrl, EnvironmentUid},
+    STATE,
+};
 
-/// The interval between one command and the other (in nanoseconds)
+/// The default interval between one command and the other sent to the same device (in nanoseconds)
 pub const COMMANDS_INTERVAL: u64 = 15_000_000_000;
+/// After how long (in nanoseconds) a running command is considered orphaned.
+/// Well above the time needed by the HTTPS outcalls of a command, even when they're retried with a new access key.
+pub const RUNNING_COMMAND_TIMEOUT: u64 = 10 * 60 * 1_000_000_000;
+
+thread_local! {
+    /// The timer armed for the next wake-up of the scheduler, with its timestamp (in nanoseconds).
+    static COMMANDS_TIMER: RefCell<Option<(TimerId, u64)>> = const { RefCell::new(None) };
+}
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
 pub struct CommandHttpArguments {
@@ -28,10 +53,28 @@ pub struct CommandHttpArguments {
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
 pub struct CommandMetadata {
-    /// The color that has been set for the light in RGB hex format.
+    /// The color that has been set for the light, as requested by the user.
     ///
     /// Example: `#ff0000` for red.
-    pub light_color: String,
+    pub light_color: Option<String>,
+    /// The hue that has been set for the light, in degrees.
+    #[serde(default)]
+    pub hue: Option<u16>,
+    /// The saturation that has been set for the light, in percent.
+    #[serde(default)]
+    pub saturation: Option<u8>,
+    /// Whether the light has been turned on or off.
+    #[serde(default)]
+    pub power: Option<bool>,
+    /// The brightness that has been set for the light, in percent.
+    #[serde(default)]
+    pub brightness: Option<u8>,
+    /// The color temperature that has been set for the light, in Kelvin.
+    #[serde(default)]
+    pub color_temperature: Option<u16>,
+    /// The transition time, in tenths of a second.
+    #[serde(default)]
+    pub transition_time: Option<u16>,
 }
 
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
@@ -40,98 +83,1290 @@ pub enum CommandStatus {
     Running,
     Completed,
     Failed(String),
+    Cancelled,
+}
+
+impl CommandStatus {
+    fn matches(&self, filter: &CommandStatusFilter) -> bool {
+        matches!(
+            (self, filter),
+            (CommandStatus::Completed, CommandStatusFilter::Completed)
+                | (CommandStatus::Failed(_), CommandStatusFilter::Failed)
+                | (CommandStatus::Cancelled, CommandStatusFilter::Cancelled)
+        )
+    }
+}
+
+/// The statuses the finished commands can be filtered by.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum CommandStatusFilter {
+    Completed,
+    Failed,
+    Cancelled,
 }
 
+/// The maximum number of attempts of a command, since each one is an HTTPS outcall paid by the canister.
+const MAX_RETRY_ATTEMPTS: u32 = 10;
+/// The minimum delay between two attempts of a command (in nanoseconds).
+const MIN_RETRY_BACKOFF: u64 = 1_000_000_000;
+
+/// How a failed command is retried.
 #[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// The delay before the first retry (in nanoseconds).
+    pub initial_backoff: u64,
+    /// How much the delay grows at each retry.
+    pub backoff_multiplier: u32,
+    /// The maximum delay between two attempts (in nanoseconds).
+    pub max_backoff: u64,
+    /// The HTTP statuses returned by the device that are worth retrying.
+    pub retryable_statuses: Vec<u16>,
+    /// Whether to retry when the HTTPS outcall is rejected.
+    pub retry_on_rejection: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: 5_000_000_000,
+            backoff_multiplier: 2,
+            max_backoff: 60_000_000_000,
+            retryable_statuses: vec![408, 429, 500, 502, 503, 504],
+            retry_on_rejection: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> Result<(), GenericError> {
+        if self.max_attempts == 0 {
+            return Err(String::from("Retry policy must allow at least one attempt"));
+        }
+        if self.max_attempts > MAX_RETRY_ATTEMPTS {
+            return Err(format!(
+                "Retry policy can't allow more than {MAX_RETRY_ATTEMPTS} attempts"
+            ));
+        }
+        if self.initial_backoff < MIN_RETRY_BACKOFF || self.max_backoff < MIN_RETRY_BACKOFF {
+            return Err(format!(
+                "Retry policy backoffs must be at least {MIN_RETRY_BACKOFF} nanoseconds"
+            ));
+        }
+        if self.max_backoff < self.initial_backoff {
+            return Err(String::from(
+                "Retry policy max backoff must be at least the initial backoff",
+            ));
+        }
+        if self.backoff_multiplier == 0 {
+            return Err(String::from(
+                "Retry policy backoff multiplier must be at least 1",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn is_retryable(&self, outcome: &RequestOutcome) -> bool {
+        match outcome {
+            RequestOutcome::Success => false,
+            RequestOutcome::HttpError(status) => self.retryable_statuses.contains(status),
+            RequestOutcome::Rejected(_) => self.retry_on_rejection,
+        }
+    }
+
+    /// Returns the delay before the next attempt, given the number of attempts made so far.
+    fn get_backoff(&self, attempts: u32) -> u64 {
+        let multiplier =
+            (self.backoff_multiplier as u64).saturating_pow(attempts.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(multiplier)
+            .min(self.max_backoff)
+    }
+}
+
+/// The outcome of a single HTTP request sent to a device.
+enum RequestOutcome {
+    Success,
+    HttpError(u16),
+    Rejected(String),
+}
+
+impl RequestOutcome {
+    fn get_error(&self) -> Option<String> {
+        match self {
+            RequestOutcome::Success => None,
+            RequestOutcome::HttpError(401) => Some(String::from("Access key is not valid.")),
+            RequestOutcome::HttpError(status) => Some(format!("HTTP status: {status}")),
+            RequestOutcome::Rejected(error) => Some(error.clone()),
+        }
+    }
+}
+
+/// A unique identifier of a command, assigned when the command is scheduled.
+pub type CommandId = u64;
+
+/// A command as it's stored, see [DeviceCommandView] for how it's returned to the callers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DeviceCommand {
+    #[serde(default)]
+    pub id: CommandId,
+    /// The environment the device was in when the command was sent.
+    #[serde(default)]
+    pub environment_uid: Option<EnvironmentUid>,
+    pub device_url: DeviceUrl,
+    /// The HTTP requests to send to the device, in order.
+    #[serde(deserialize_with = "deserialize_http_arguments")]
+    http_arguments: Vec<CommandHttpArguments>,
+    pub schedule_timestamp: u64,
+    #[serde(default = "default_schedule")]
+    pub schedule: CommandSchedule,
+    pub sender: Principal,
+    pub metadata: Option<CommandMetadata>,
+    pub status: CommandStatus,
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    /// How many times the command has been executed.
+    #[serde(default)]
+    pub attempts: u32,
+    /// When the last attempt started (in nanoseconds).
+    #[serde(default)]
+    pub started_timestamp: Option<u64>,
+    /// When the command has completed, failed or been cancelled (in nanoseconds).
+    #[serde(default)]
+    pub finished_timestamp: Option<u64>,
+    /// The error of the last failed attempt.
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+fn default_schedule() -> CommandSchedule {
+    CommandSchedule::Now
+}
+
+/// A command as returned to the callers, without the requests sent to the device,
+/// since their headers contain the access keys of the device.
+#[derive(Clone, Debug, CandidType, Serialize)]
+pub struct DeviceCommandView {
+    pub id: CommandId,
+    pub environment_uid: Option<EnvironmentUid>,
     pub device_url: DeviceUrl,
-    http_arguments: CommandHttpArguments,
     pub schedule_timestamp: u64,
+    pub schedule: CommandSchedule,
     pub sender: Principal,
     pub metadata: Option<CommandMetadata>,
     pub status: CommandStatus,
+    pub retry_policy: RetryPolicy,
+    pub attempts: u32,
+    pub started_timestamp: Option<u64>,
+    pub finished_timestamp: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl From<DeviceCommand> for DeviceCommandView {
+    fn from(command: DeviceCommand) -> Self {
+        Self {
+            id: command.id,
+            environment_uid: command.environment_uid,
+            device_url: command.device_url,
+            schedule_timestamp: command.schedule_timestamp,
+            schedule: command.schedule,
+            sender: command.sender,
+            metadata: command.metadata,
+            status: command.status,
+            retry_policy: command.retry_policy,
+            attempts: command.attempts,
+            started_timestamp: command.started_timestamp,
+            finished_timestamp: command.finished_timestamp,
+            last_error: command.last_error,
+        }
+    }
+}
+
+/// Commands stored before a command could send multiple requests have a single request.
+fn deserialize_http_arguments<'de, D>(
+    deserializer: D,
+) -> Result<Vec<CommandHttpArguments>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(CommandHttpArguments),
+        Many(Vec<CommandHttpArguments>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(http_arguments) => vec![http_arguments],
+        OneOrMany::Many(http_arguments) => http_arguments,
+    })
 }
 
 impl DeviceCommand {
+    /// Commands finished before the finish timestamp was recorded fall back to the schedule timestamp.
+    fn get_finished_timestamp(&self) -> u64 {
+        self.finished_timestamp.unwrap_or(self.schedule_timestamp)
+    }
+
     pub fn new(
+        environment_uid: EnvironmentUid,
         device_url: DeviceUrl,
-        http_arguments: CommandHttpArguments,
+        http_arguments: Vec<CommandHttpArguments>,
         schedule_timestamp: u64,
         sender: Principal,
         metadata: Option<CommandMetadata>,
+        retry_policy: RetryPolicy,
     ) -> Self {
         Self {
+            id: 0, // initializing the id to 0 because it's set in the schedule_command function
+            environment_uid: Some(environment_uid),
             device_url,
             http_arguments,
             schedule_timestamp,
+            schedule: CommandSchedule::Now,
             sender,
             metadata,
             status: CommandStatus::Scheduled,
+            retry_policy,
+            attempts: 0,
+            started_timestamp: None,
+            finished_timestamp: None,
+            last_error: None,
+        }
+    }
+}
+
+/// Returned to the caller when a command is scheduled, to track it.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct ScheduleCommandReceipt {
+    pub command_id: CommandId,
+    /// When the command is expected to be executed (in nanoseconds).
+    pub schedule_timestamp: u64,
+    /// The number of commands that will be executed on the device before this one.
+    pub queue_position: u64,
+}
+
+/// The commands scheduled for a single device.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DeviceQueue {
+    /// The minimum interval between two commands sent to the device (in nanoseconds).
+    pub interval: u64,
+    /// When the last command for the device has been started (in nanoseconds).
+    #[serde(default)]
+    last_started_timestamp: u64,
+}
+
+impl Default for DeviceQueue {
+    fn default() -> Self {
+        Self {
+            interval: COMMANDS_INTERVAL,
+            last_started_timestamp: 0,
+        }
+    }
+}
+
+impl DeviceQueue {
+    /// Returns the earliest timestamp the next command of the device can be started at.
+    fn get_earliest_start_timestamp(&self) -> u64 {
+        self.last_started_timestamp.saturating_add(self.interval)
+    }
+}
+
+/// The key of the indexes of the commands, ordered by the indexed value and then by command id.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct IndexKey<T>(T, CommandId);
+
+impl_cbor_storable!(
+    IndexKey<DeviceUrl>,
+    IndexKey<Principal>,
+    IndexKey<(DeviceUrl, u64)>
+);
+
+/// How far ahead a command can be scheduled (in nanoseconds).
+const MAX_SCHEDULE_HORIZON: u64 = 365 * 24 * 60 * 60 * 1_000_000_000;
+
+/// When a command should be executed.
+///
+/// Commands for the same device are always at least the device interval apart,
+/// so the actual schedule timestamp may be later than the requested one.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub enum CommandSchedule {
+    /// As soon as possible.
+    Now,
+    /// At the given timestamp (in nanoseconds).
+    At(u64),
+    /// After the given delay (in nanoseconds).
+    After(u64),
+    /// Every time the given cron expression matches, see [CronSchedule].
+    Cron(String),
+}
+
+impl CommandSchedule {
+    pub fn validate(&self) -> Result<(), GenericError> {
+        self.get_requested_timestamp(time()).map(|_| ())
+    }
+
+    /// Returns the timestamp requested by the schedule, starting from the given one.
+    ///
+    /// Returns an error if it's more than [MAX_SCHEDULE_HORIZON] away. Cron occurrences are exempt,
+    /// since yearly expressions or leap days can be further away and [CronSchedule] bounds how far it looks.
+    fn get_requested_timestamp(&self, now: u64) -> Result<u64, GenericError> {
+        let requested_timestamp = self.get_unbounded_timestamp(now)?;
+        if !matches!(self, CommandSchedule::Cron(_))
+            && requested_timestamp > now.saturating_add(MAX_SCHEDULE_HORIZON)
+        {
+            return Err(String::from(
+                "Commands can't be scheduled more than a year ahead",
+            ));
+        }
+
+        Ok(requested_timestamp)
+    }
+
+    fn get_unbounded_timestamp(&self, now: u64) -> Result<u64, GenericError> {
+        match self {
+            CommandSchedule::Now => Ok(now),
+            CommandSchedule::At(ts) if *ts < now => {
+                Err(String::from("Schedule timestamp is in the past"))
+            }
+            CommandSchedule::At(ts) => Ok(*ts),
+            CommandSchedule::After(delay) => Ok(now.saturating_add(*delay)),
+            CommandSchedule::Cron(expression) => CronSchedule::from_str(expression)?
+                .next_after(now)
+                .ok_or_else(|| format!("Cron expression {expression} never matches")),
+        }
+    }
+}
+
+/// The maximum number of finished commands that can be kept.
+const MAX_HISTORY_COMMANDS: u64 = 10_000;
+/// The maximum number of commands that a principal can have scheduled at the same time,
+/// so that one operator can't prevent the others from scheduling their commands.
+const MAX_SCHEDULED_COMMANDS_PER_SENDER: usize = 1_000;
+
+/// How many finished commands are kept, and for how long.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct HistoryRetention {
+    /// The maximum number of finished commands kept, the oldest are removed first.
+    pub max_commands: u64,
+    /// How long a finished command is kept (in nanoseconds).
+    pub max_age: u64,
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        Self {
+            max_commands: 1_000,
+            max_age: 30 * 24 * 60 * 60 * 1_000_000_000, // 30 days
         }
     }
 }
 
-#[derive(Clone, Default, CandidType, Serialize, Deserialize)]
+impl HistoryRetention {
+    pub fn validate(&self) -> Result<(), GenericError> {
+        if self.max_commands > MAX_HISTORY_COMMANDS {
+            return Err(format!(
+                "History can't keep more than {MAX_HISTORY_COMMANDS} commands"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The default number of commands in a history page.
+const HISTORY_PAGE_SIZE: u32 = 20;
+/// The maximum number of commands in a history page.
+const MAX_HISTORY_PAGE_SIZE: u32 = 100;
+
+/// The filters and the position of a page of the finished commands.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct HistoryQuery {
+    pub device_url: Option<DeviceUrl>,
+    pub sender: Option<Principal>,
+    pub status: Option<CommandStatusFilter>,
+    /// Only the commands finished at or after this timestamp (in nanoseconds).
+    pub from_timestamp: Option<u64>,
+    /// Only the commands finished before this timestamp (in nanoseconds).
+    pub to_timestamp: Option<u64>,
+    /// Only the commands finished before this position, as returned in [HistoryPage::next_cursor].
+    pub cursor: Option<HistoryCursor>,
+    /// The maximum number of commands to return, up to 100.
+    pub limit: Option<u32>,
+}
+
+/// The position of a finished command in the history, which is ordered by finish timestamp.
+///
+/// The id breaks the ties between the commands finished at the same time.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize, Serialize,
+)]
+pub struct HistoryCursor {
+    pub finished_timestamp: u64,
+    pub id: CommandId,
+}
+
+/// A page of the finished commands, from the most recently finished to the least recently finished.
+#[derive(CandidType, Serialize)]
+pub struct HistoryPage {
+    pub commands: Vec<DeviceCommandView>,
+    /// The cursor to get the next page with, if there are more commands.
+    pub next_cursor: Option<HistoryCursor>,
+}
+
+/// The due command of a device claimed by an execution, which is the only one allowed to start it.
+///
+/// The lease expires in case the execution never releases it, e.g. because it trapped.
+#[derive(Clone, Debug, Default)]
+struct CommandsLease {
+    /// Identifies the execution holding the lease, see [CommandClaim].
+    lease_id: u64,
+    /// The claimed command, none if it has been cancelled or rescheduled since.
+    command_id: Option<CommandId>,
+    expiration_timestamp: u64,
+}
+
+/// A command claimed by an execution, returned by [DeviceCommands::claim_commands_to_run].
+///
+/// Once the lease expires, the device can be claimed by another execution with a new lease id,
+/// so that the late execution can't start the command or release the lease of the new one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommandClaim {
+    pub command_id: CommandId,
+    lease_id: u64,
+}
+
+/// The settings and counters of the commands, small enough to be saved to stable memory on upgrades.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct CommandsSettings {
+    next_command_id: CommandId,
+    queues: BTreeMap<DeviceUrl, DeviceQueue>,
+    history_retention: HistoryRetention,
+}
+
+/// The commands as they were dumped to stable memory before moving to stable structures.
+#[derive(Deserialize)]
+pub struct LegacyDeviceCommands {
+    #[serde(default)]
+    next_command_id: CommandId,
+    #[serde(default)]
+    queues: BTreeMap<DeviceUrl, DeviceQueue>,
+    scheduled_commands: BTreeMap<CommandId, DeviceCommand>,
+    running_commands: BTreeMap<CommandId, DeviceCommand>,
+    finished_commands: BTreeMap<CommandId, DeviceCommand>,
+    #[serde(default)]
+    history_retention: HistoryRetention,
+}
+
+impl_cbor_storable!(DeviceCommand);
+
+/// Commands are stored by their unique id, so that two commands can never overwrite each other.
+///
+/// The commands and their indexes live in stable memory, so that upgrades don't depend on how many commands there are.
 pub struct DeviceCommands {
-    pub scheduled_commands: BTreeMap<u64, DeviceCommand>,
-    pub running_commands: BTreeMap<u64, DeviceCommand>,
-    pub finished_commands: BTreeMap<u64, DeviceCommand>,
+    settings: CommandsSettings,
+    pub scheduled_commands: StableBTreeMap<CommandId, DeviceCommand, Memory>,
+    pub running_commands: StableBTreeMap<CommandId, DeviceCommand, Memory>,
+    pub finished_commands: StableBTreeMap<CommandId, DeviceCommand, Memory>,
+    /// Index of the scheduled commands, by schedule timestamp.
+    commands_by_timestamp: StableBTreeMap<(u64, CommandId), (), Memory>,
+    /// Index of the finished commands, by finish timestamp from the most recent,
+    /// since the stable structures can only be iterated forward.
+    finished_by_timestamp: StableBTreeMap<Reverse<(u64, CommandId)>, (), Memory>,
+    /// Index of all the commands, by device.
+    commands_by_device: StableBTreeMap<IndexKey<DeviceUrl>, (), Memory>,
+    /// Index of all the commands, by sender.
+    commands_by_sender: StableBTreeMap<IndexKey<Principal>, (), Memory>,
+    /// Index of the scheduled commands of each device, by schedule timestamp.
+    queued_commands: StableBTreeMap<IndexKey<(DeviceUrl, u64)>, (), Memory>,
+    /// Index of the scheduled commands, by sender.
+    scheduled_by_sender: StableBTreeMap<IndexKey<Principal>, (), Memory>,
+    /// The commands claimed by the executions in progress, by device.
+    leases: BTreeMap<DeviceUrl, CommandsLease>,
+    next_lease_id: u64,
 }
 
 impl DeviceCommands {
-    /// Schedules the next command for the closest timestamp between
-    /// now + 15 seconds and last command schedule timestamp + 15 seconds
-    pub fn schedule_command(&mut self, mut c: DeviceCommand) {
-        let now = time();
-        let last_command_ts = self.get_last_command_timestamp();
-        if now > last_command_ts {
-            c.schedule_timestamp = now + COMMANDS_INTERVAL;
-        } else {
-            c.schedule_timestamp = last_command_ts + COMMANDS_INTERVAL;
+    /// Opens the commands stored in stable memory.
+    pub fn init(settings: CommandsSettings) -> Self {
+        Self {
+            settings,
+            scheduled_commands: StableBTreeMap::init(get_memory(SCHEDULED_COMMANDS_MEMORY_ID)),
+            running_commands: StableBTreeMap::init(get_memory(RUNNING_COMMANDS_MEMORY_ID)),
+            finished_commands: StableBTreeMap::init(get_memory(FINISHED_COMMANDS_MEMORY_ID)),
+            commands_by_timestamp: StableBTreeMap::init(get_memory(
+                SCHEDULED_BY_TIMESTAMP_MEMORY_ID,
+            )),
+            finished_by_timestamp: StableBTreeMap::init(get_memory(
+                FINISHED_BY_TIMESTAMP_MEMORY_ID,
+            )),
+            commands_by_device: StableBTreeMap::init(get_memory(COMMANDS_BY_DEVICE_MEMORY_ID)),
+            commands_by_sender: StableBTreeMap::init(get_memory(COMMANDS_BY_SENDER_MEMORY_ID)),
+            queued_commands: StableBTreeMap::init(get_memory(QUEUED_COMMANDS_MEMORY_ID)),
+            scheduled_by_sender: StableBTreeMap::init(get_memory(SCHEDULED_BY_SENDER_MEMORY_ID)),
+            leases: BTreeMap::new(),
+            next_lease_id: 0,
         }
+    }
+
+    pub fn get_settings(&self) -> CommandsSettings {
+        self.settings.clone()
+    }
 
-        print(format!("Command scheduled for {}", c.schedule_timestamp));
+    pub fn set_settings(&mut self, settings: CommandsSettings) {
+        self.settings = settings;
+    }
 
-        self.scheduled_commands
-            .entry(c.schedule_timestamp)
-            .or_insert(c);
+    /// Moves the commands dumped by a previous version into stable memory, returning their settings.
+    /// The indexes are built by a later migration, see [DeviceCommands::build_indexes].
+    ///
+    /// Commands stored before they had an id are keyed by their schedule timestamp,
+    /// which becomes their id.
+    pub fn import_legacy(&mut self, legacy: LegacyDeviceCommands) -> CommandsSettings {
+        let mut next_command_id = legacy.next_command_id;
+        for (commands, legacy_commands) in [
+            (&mut self.scheduled_commands, legacy.scheduled_commands),
+            (&mut self.running_commands, legacy.running_commands),
+            (&mut self.finished_commands, legacy.finished_commands),
+        ] {
+            for (id, mut command) in legacy_commands {
+                command.id = id;
+                commands.insert(id, command);
+                next_command_id = next_command_id.max(id.saturating_add(1));
+            }
+        }
+
+        CommandsSettings {
+            next_command_id,
+            queues: legacy.queues,
+            history_retention: legacy.history_retention,
+        }
+    }
+
+    /// Schedules the command for the closest timestamp to the requested one
+    /// that is at least one device interval away from the other commands of the device.
+    ///
+    /// Commands for different devices don't wait for each other.
+    /// Recurring commands are scheduled again every time they're executed.
+    pub fn schedule_command(
+        &mut self,
+        mut c: DeviceCommand,
+        schedule: CommandSchedule,
+        now: u64,
+    ) -> Result<ScheduleCommandReceipt, GenericError> {
+        let requested_timestamp = schedule.get_requested_timestamp(now)?;
+        let sender_scheduled_count = self
+            .scheduled_by_sender
+            .range(IndexKey(c.sender, 0)..=IndexKey(c.sender, CommandId::MAX))
+            .take(MAX_SCHEDULED_COMMANDS_PER_SENDER)
+            .count();
+        if sender_scheduled_count >= MAX_SCHEDULED_COMMANDS_PER_SENDER {
+            return Err(format!(
+                "Can't schedule more than {MAX_SCHEDULED_COMMANDS_PER_SENDER} commands, wait for your scheduled ones to run"
+            ));
+        }
+
+        c.schedule_timestamp = self.get_free_slot(&c.device_url, requested_timestamp);
+        c.schedule = schedule;
+
+        c.id = self.settings.next_command_id;
+        self.settings.next_command_id += 1;
+
+        log(format!(
+            "Command {} for {} scheduled for {}",
+            c.id, c.device_url, c.schedule_timestamp
+        ));
+
+        let receipt = ScheduleCommandReceipt {
+            command_id: c.id,
+            schedule_timestamp: c.schedule_timestamp,
+            queue_position: self.get_queue_position(&c),
+        };
+
+        self.index_command(&c);
+        self.index_scheduled_command(&c);
+        if self.scheduled_commands.insert(c.id, c).is_some() {
+            // trapping rolls back the call, so that the caller knows the command hasn't been accepted
+            trap(&format!("Command {} already exists", receipt.command_id));
+        }
+
+        Ok(receipt)
+    }
+
+    /// Returns the number of commands scheduled for the same device before the given one.
+    fn get_queue_position(&self, c: &DeviceCommand) -> u64 {
+        self.get_queued_commands(&c.device_url, 0)
+            .take_while(|queued| *queued < (c.schedule_timestamp, c.id))
+            .count() as u64
+    }
+
+    /// Returns the scheduled commands of the device from the given schedule timestamp, in the order they run.
+    fn get_queued_commands(
+        &self,
+        device_url: &DeviceUrl,
+        from_timestamp: u64,
+    ) -> impl Iterator<Item = (u64, CommandId)> + '_ {
+        self.queued_commands
+            .range(
+                IndexKey((device_url.clone(), from_timestamp), 0)
+                    ..=IndexKey((device_url.clone(), u64::MAX), CommandId::MAX),
+            )
+            .map(|(IndexKey((_, ts), id), _)| (ts, id))
+    }
+
+    /// Returns the closest timestamp to the requested one that is at least
+    /// one interval away from the other commands of the device.
+    fn get_free_slot(&mut self, device_url: &DeviceUrl, requested_timestamp: u64) -> u64 {
+        let queue = self
+            .settings
+            .queues
+            .entry(device_url.clone())
+            .or_default()
+            .clone();
+        let mut slot = requested_timestamp.max(queue.get_earliest_start_timestamp());
+
+        for (ts, _) in self.get_queued_commands(device_url, slot.saturating_sub(queue.interval)) {
+            if ts >= slot.saturating_add(queue.interval) {
+                // there's enough room before this command
+                break;
+            }
+            slot = slot.max(ts.saturating_add(queue.interval));
+        }
+
+        slot
     }
 
-    pub fn get_commands_to_run(&self) -> Vec<DeviceCommand> {
-        let current_timestamp = time();
+    /// Returns when the first scheduled command of the device can be started,
+    /// which is at least one interval after the last one started.
+    fn get_next_start_timestamp(&self, device_url: &DeviceUrl, queue: &DeviceQueue) -> Option<u64> {
+        self.get_queued_commands(device_url, 0)
+            .next()
+            .map(|(ts, _)| ts.max(queue.get_earliest_start_timestamp()))
+    }
 
+    /// Sets the minimum interval between two commands sent to the device (in nanoseconds).
+    ///
+    /// The interval applies to the commands scheduled from now on.
+    pub fn set_device_interval(&mut self, device_url: DeviceUrl, interval: u64) {
+        self.settings.queues.entry(device_url).or_default().interval = interval;
+    }
+
+    /// Claims the first due command of each device.
+    ///
+    /// A device whose command is still claimed by another execution is skipped,
+    /// so that each command is dispatched once and the commands of a device run in order.
+    /// A device that started a command less than one interval ago is skipped too,
+    /// so that the commands that piled up, e.g. because of a slow command or an upgrade, are not sent back to back.
+    ///
+    /// The claimed commands must be started with [DeviceCommands::start_command]
+    /// and the lease released with [DeviceCommands::release_lease] when the execution ends.
+    pub fn claim_commands_to_run(&mut self, now: u64) -> BTreeMap<DeviceUrl, CommandClaim> {
+        let mut commands_to_run: BTreeMap<DeviceUrl, CommandClaim> = BTreeMap::new();
+
+        self.leases
+            .retain(|_, lease| lease.expiration_timestamp > now);
+
+        let due_commands: Vec<(DeviceUrl, CommandId)> = self
+            .settings
+            .queues
+            .iter()
+            .filter(|(device_url, queue)| {
+                !self.leases.contains_key(*device_url)
+                    && self.get_next_start_timestamp(device_url, queue) <= Some(now)
+            })
+            .filter_map(|(device_url, _)| {
+                self.get_queued_commands(device_url, 0)
+                    .next()
+                    .map(|(_, id)| (device_url.clone(), id))
+            })
+            .collect();
+
+        for (device_url, command_id) in due_commands {
+            commands_to_run.insert(
+                device_url,
+                CommandClaim {
+                    command_id,
+                    lease_id: self.next_lease_id,
+                },
+            );
+            self.next_lease_id += 1;
+        }
+
+        for (device_url, claim) in commands_to_run.iter() {
+            self.leases.insert(
+                device_url.clone(),
+                CommandsLease {
+                    lease_id: claim.lease_id,
+                    command_id: Some(claim.command_id),
+                    expiration_timestamp: now.saturating_add(RUNNING_COMMAND_TIMEOUT),
+                },
+            );
+        }
+
+        commands_to_run
+    }
+
+    /// Returns when the scheduler has something to do next (in nanoseconds):
+    /// run a due command, take over an expired lease, recover an orphaned running command
+    /// or remove a finished command that has become too old.
+    ///
+    /// The commands of the devices claimed by an execution are not considered,
+    /// the timer is armed again when the execution ends.
+    pub fn get_next_wakeup_timestamp(&self) -> Option<u64> {
+        let next_command_timestamp = self
+            .settings
+            .queues
+            .iter()
+            .filter(|(device_url, _)| !self.leases.contains_key(*device_url))
+            .filter_map(|(device_url, queue)| self.get_next_start_timestamp(device_url, queue))
+            .min();
+        let next_lease_expiration = self
+            .leases
+            .values()
+            .map(|lease| lease.expiration_timestamp)
+            .min();
+        let next_running_timeout = self
+            .running_commands
+            .iter()
+            .map(|(_, c)| {
+                c.started_timestamp
+                    .unwrap_or_default()
+                    .saturating_add(RUNNING_COMMAND_TIMEOUT)
+            })
+            .min();
+
+        // the index is ordered from the most recent, so the oldest command is the last one
+        let next_history_expiration = self.finished_by_timestamp.last_key_value().map(
+            |(Reverse((finished_timestamp, _)), _)| {
+                finished_timestamp.saturating_add(self.settings.history_retention.max_age)
+            },
+        );
+
+        [
+            next_command_timestamp,
+            next_lease_expiration,
+            next_running_timeout,
+            next_history_expiration,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Releases the lease on the commands of the device, once the execution that claimed them ends.
+    ///
+    /// The lease is left alone if it has expired and the device has been claimed again in the meantime.
+    pub fn release_lease(&mut self, device_url: &DeviceUrl, claim: &CommandClaim) {
+        if self
+            .leases
+            .get(device_url)
+            .is_some_and(|lease| lease.lease_id == claim.lease_id)
+        {
+            self.leases.remove(device_url);
+        }
+    }
+
+    /// Removes the command from the lease that claimed it, if any.
+    fn unclaim_command(&mut self, c: &DeviceCommand) {
+        if let Some(lease) = self.leases.get_mut(&c.device_url) {
+            if lease.command_id == Some(c.id) {
+                lease.command_id = None;
+            }
+        }
+    }
+
+    /// Returns the command with the given id, in whatever state it is.
+    pub fn get_command(&self, id: CommandId) -> Option<DeviceCommand> {
         self.scheduled_commands
-            .range(..current_timestamp)
-            .map(|(_, v)| v.clone())
-            .collect()
+            .get(&id)
+            .or_else(|| self.running_commands.get(&id))
+            .or_else(|| self.finished_commands.get(&id))
+    }
+
+    /// Returns a page of the scheduled commands ordered by schedule timestamp,
+    /// along with the running commands and the most recently finished ones.
+    pub fn get_commands_view(
+        &self,
+        query: &CommandsQuery,
+        can_view: impl Fn(&DeviceCommand) -> bool,
+    ) -> CommandsView {
+        let limit = query
+            .limit
+            .unwrap_or(MAX_SCHEDULED_PAGE_SIZE)
+            .clamp(1, MAX_SCHEDULED_PAGE_SIZE) as usize;
+        let range = match query.cursor {
+            Some(cursor) => (
+                Bound::Excluded((cursor.schedule_timestamp, cursor.id)),
+                Bound::Unbounded,
+            ),
+            None => (Bound::Unbounded, Bound::Unbounded),
+        };
+
+        let mut scheduled_commands: Vec<(ScheduleCursor, DeviceCommand)> = self
+            .commands_by_timestamp
+            .range(range)
+            .filter_map(|((schedule_timestamp, id), _)| {
+                let command = self.scheduled_commands.get(&id)?;
+                let cursor = ScheduleCursor {
+                    schedule_timestamp,
+                    id,
+                };
+                Some((cursor, command))
+            })
+            .filter(|(_, c)| can_view(c))
+            .take(limit + 1)
+            .collect();
+
+        let next_cursor = if scheduled_commands.len() > limit {
+            scheduled_commands.truncate(limit);
+            scheduled_commands.last().map(|(cursor, _)| *cursor)
+        } else {
+            None
+        };
+
+        // the index is ordered from the most recent, but they're returned from the oldest to the newest
+        let mut finished_commands: Vec<DeviceCommand> = self
+            .finished_by_timestamp
+            .iter()
+            .filter_map(|(Reverse((_, id)), _)| self.finished_commands.get(&id))
+            .filter(&can_view)
+            .take(RECENTLY_FINISHED_COUNT)
+            .collect();
+        finished_commands.reverse();
+
+        let to_view = |c: DeviceCommand| (c.id, DeviceCommandView::from(c));
+        CommandsView {
+            scheduled_commands: scheduled_commands
+                .into_iter()
+                .map(|(_, c)| to_view(c))
+                .collect(),
+            running_commands: self
+                .running_commands
+                .iter()
+                .map(|(_, c)| c)
+                .filter(&can_view)
+                .map(to_view)
+                .collect(),
+            finished_commands: finished_commands.into_iter().map(to_view).collect(),
+            next_cursor,
+        }
+    }
+
+    /// Moves the command from the scheduled to the running ones, if it's claimed and still due at the given timestamp.
+    pub fn start_command(&mut self, claim: &CommandClaim, now: u64) -> Option<DeviceCommand> {
+        let id = claim.command_id;
+        let command = self.scheduled_commands.get(&id)?;
+        // only the execution that claimed the command can start it
+        let is_claimed = self
+            .leases
+            .get(&command.device_url)
+            .is_some_and(|lease| lease.lease_id == claim.lease_id && lease.command_id == Some(id));
+        if !is_claimed {
+            return None;
+        }
+        if command.schedule_timestamp > now {
+            return None;
+        }
+
+        let mut command = self.scheduled_commands.remove(&id)?;
+        self.unindex_scheduled_command(&command);
+        if let Some(queue) = self.settings.queues.get_mut(&command.device_url) {
+            queue.last_started_timestamp = now;
+        }
+
+        command.status = CommandStatus::Running;
+        command.attempts += 1;
+        command.started_timestamp = Some(now);
+        self.running_commands.insert(id, command.clone());
+
+        Some(command)
+    }
+
+    /// Moves the command from the running to the finished ones.
+    ///
+    /// Recurring commands are scheduled again for their next occurrence, as a new command.
+    pub fn finish_command(&mut self, command: DeviceCommand, now: u64) {
+        if !self.remove_running_command(&command) {
+            return;
+        }
+
+        if let CommandSchedule::Cron(_) = command.schedule {
+            let mut next_command = DeviceCommand::new(
+                String::new(),
+                command.device_url.clone(),
+                command.http_arguments.clone(),
+                0,
+                command.sender,
+                command.metadata.clone(),
+                command.retry_policy.clone(),
+            );
+            next_command.environment_uid = command.environment_uid.clone();
+            if let Err(e) = self.schedule_command(next_command, command.schedule.clone(), now) {
+                log(format!(
+                    "Failed to schedule the next occurrence of command {}: {e}",
+                    command.id
+                ));
+            }
+        }
+
+        self.record_finished_command(command, now);
+    }
+
+    fn record_finished_command(&mut self, mut command: DeviceCommand, now: u64) {
+        command.finished_timestamp = Some(now);
+        self.finished_by_timestamp
+            .insert(Reverse((command.get_finished_timestamp(), command.id)), ());
+        self.finished_commands.insert(command.id, command);
+
+        self.prune_history(now);
+    }
+
+    /// Sets how many finished commands are kept, and for how long, pruning the history right away.
+    pub fn set_history_retention(&mut self, retention: HistoryRetention, now: u64) {
+        self.settings.history_retention = retention;
+        self.prune_history(now);
+    }
+
+    /// Removes the finished commands that are too old or exceed the maximum number.
+    pub fn prune_history(&mut self, current_timestamp: u64) {
+        // the index is ordered from the most recent, so the oldest command is the last one
+        while let Some((Reverse((finished_timestamp, id)), _)) =
+            self.finished_by_timestamp.last_key_value()
+        {
+            let expiration_timestamp =
+                finished_timestamp.saturating_add(self.settings.history_retention.max_age);
+            if expiration_timestamp > current_timestamp
+                && self.finished_by_timestamp.len() <= self.settings.history_retention.max_commands
+            {
+                break;
+            }
+
+            self.finished_by_timestamp
+                .remove(&Reverse((finished_timestamp, id)));
+            if let Some(command) = self.finished_commands.remove(&id) {
+                self.unindex_command(&command);
+            }
+        }
+    }
+
+    /// Returns a page of the finished commands matching the query,
+    /// from the most recently finished to the least recently finished.
+    ///
+    /// The commands the caller can't view are skipped.
+    pub fn get_history(
+        &self,
+        query: &HistoryQuery,
+        can_view: impl Fn(&DeviceCommand) -> bool,
+    ) -> HistoryPage {
+        let limit = query
+            .limit
+            .unwrap_or(HISTORY_PAGE_SIZE)
+            .clamp(1, MAX_HISTORY_PAGE_SIZE) as usize;
+
+        // the time range and the cursor bound the scan of the finish timestamp index,
+        // which is ordered from the most recent
+        let oldest = Reverse((query.from_timestamp.unwrap_or(0), 0));
+        let newest = query
+            .to_timestamp
+            .map(|to| (to, 0))
+            .into_iter()
+            .chain(query.cursor.map(|c| (c.finished_timestamp, c.id)))
+            .min()
+            .map(Reverse);
+        let range = match newest {
+            // an empty range when the bounds are swapped
+            Some(newest) => (Bound::Excluded(newest.min(oldest)), Bound::Included(oldest)),
+            None => (Bound::Unbounded, Bound::Included(oldest)),
+        };
+
+        let mut commands: Vec<(HistoryCursor, DeviceCommand)> = self
+            .finished_by_timestamp
+            .range(range)
+            // the commands are decoded only when they match the indexes
+            .filter(|(Reverse((_, id)), _)| {
+                query.device_url.as_ref().is_none_or(|device_url| {
+                    self.commands_by_device
+                        .contains_key(&IndexKey(device_url.clone(), *id))
+                }) && query.sender.is_none_or(|sender| {
+                    self.commands_by_sender.contains_key(&IndexKey(sender, *id))
+                })
+            })
+            .filter_map(|(Reverse((finished_timestamp, id)), _)| {
+                let command = self.finished_commands.get(&id)?;
+                let cursor = HistoryCursor {
+                    finished_timestamp,
+                    id,
+                };
+                Some((cursor, command))
+            })
+            .filter(|(_, c)| {
+                can_view(c)
+                    && query
+                        .status
+                        .as_ref()
+                        .is_none_or(|status| c.status.matches(status))
+            })
+            .take(limit + 1)
+            .collect();
+
+        let next_cursor = if commands.len() > limit {
+            commands.truncate(limit);
+            commands.last().map(|(cursor, _)| *cursor)
+        } else {
+            None
+        };
+
+        HistoryPage {
+            commands: commands.into_iter().map(|(_, c)| c.into()).collect(),
+            next_cursor,
+        }
+    }
+
+    /// Moves a failed command from the running to the scheduled ones, to retry it after the policy backoff.
+    pub fn retry_command(&mut self, mut command: DeviceCommand, now: u64) {
+        if !self.remove_running_command(&command) {
+            return;
+        }
+
+        let backoff = command.retry_policy.get_backoff(command.attempts);
+        command.schedule_timestamp =
+            self.get_free_slot(&command.device_url, now.saturating_add(backoff));
+        command.status = CommandStatus::Scheduled;
+
+        log(format!(
+            "Command {} will be retried at {} (attempt {})",
+            command.id,
+            command.schedule_timestamp,
+            command.attempts + 1
+        ));
+
+        self.index_scheduled_command(&command);
+        self.scheduled_commands.insert(command.id, command);
+    }
+
+    /// Removes the command from the running ones, returning false if the given attempt is not running anymore,
+    /// which happens when a command completes after being recovered, even if it has been started again since.
+    fn remove_running_command(&mut self, command: &DeviceCommand) -> bool {
+        let is_running = self.running_commands.get(&command.id).is_some_and(|c| {
+            c.attempts == command.attempts && c.started_timestamp == command.started_timestamp
+        });
+        if !is_running {
+            log(format!(
+                "Attempt {} of command {} is not running anymore, ignoring its result: {:?}",
+                command.attempts, command.id, command.status
+            ));
+            return false;
+        }
+
+        self.running_commands.remove(&command.id);
+        true
+    }
+
+    /// Recovers the running commands started at or before the given timestamp, which are not going to complete:
+    /// the canister was upgraded or the execution trapped while waiting for the device.
+    ///
+    /// As we don't know whether the device received the requests, the commands are treated as rejected outcalls:
+    /// they're scheduled again if their retry policy allows it, otherwise they fail.
+    pub fn recover_running_commands(&mut self, started_until: u64, now: u64) {
+        let orphaned_commands: Vec<DeviceCommand> = self
+            .running_commands
+            .iter()
+            .map(|(_, c)| c)
+            .filter(|c| c.started_timestamp.unwrap_or_default() <= started_until)
+            .collect();
+
+        for mut command in orphaned_commands {
+            let error = String::from("Execution interrupted before completion");
+            log(format!("Recovering command {}: {error}", command.id));
+
+            let outcome = RequestOutcome::Rejected(error.clone());
+            command.last_error = Some(error.clone());
+
+            if command.retry_policy.is_retryable(&outcome)
+                && command.attempts < command.retry_policy.max_attempts
+            {
+                self.retry_command(command, now);
+            } else {
+                command.status = CommandStatus::Failed(error);
+                self.finish_command(command, now);
+            }
+        }
+    }
+
+    /// Withdraws a scheduled command, recording it in the finished ones as cancelled.
+    ///
+    /// Cancelling an occurrence of a recurring command stops the recurrence.
+    pub fn cancel_command(&mut self, id: CommandId, now: u64) -> Result<(), GenericError> {
+        let mut command = self
+            .scheduled_commands
+            .remove(&id)
+            .ok_or_else(|| format!("Command {id} is not scheduled"))?;
+        self.unindex_scheduled_command(&command);
+        self.unclaim_command(&command);
+
+        command.status = CommandStatus::Cancelled;
+        self.record_finished_command(command, now);
+
+        log(format!("Command {id} cancelled"));
+
+        Ok(())
+    }
+
+    /// Moves a scheduled command to the closest timestamp to the requested one
+    /// that is at least one device interval away from the other commands of the device.
+    ///
+    /// Rescheduling an occurrence of a recurring command doesn't affect the next ones.
+    pub fn reschedule_command(
+        &mut self,
+        id: CommandId,
+        timestamp: u64,
+        now: u64,
+    ) -> Result<ScheduleCommandReceipt, GenericError> {
+        let requested_timestamp = CommandSchedule::At(timestamp).get_requested_timestamp(now)?;
+
+        let mut command = self
+            .scheduled_commands
+            .remove(&id)
+            .ok_or_else(|| format!("Command {id} is not scheduled"))?;
+        self.unindex_scheduled_command(&command);
+        // the execution that claimed the command must not start it at its old timestamp
+        self.unclaim_command(&command);
+
+        command.schedule_timestamp = self.get_free_slot(&command.device_url, requested_timestamp);
+        if !matches!(command.schedule, CommandSchedule::Cron(_)) {
+            command.schedule = CommandSchedule::At(timestamp);
+        }
+
+        log(format!(
+            "Command {id} rescheduled for {}",
+            command.schedule_timestamp
+        ));
+
+        let receipt = ScheduleCommandReceipt {
+            command_id: id,
+            schedule_timestamp: command.schedule_timestamp,
+            queue_position: self.get_queue_position(&command),
+        };
+
+        self.index_scheduled_command(&command);
+        self.scheduled_commands.insert(id, command);
+
+        Ok(receipt)
     }
 
-    fn get_last_command_timestamp(&self) -> u64 {
-        match self.scheduled_commands.last_key_value() {
-            Some((k, _)) => *k,
-            // if there's no command, now is reasonably the closest time
-            None => time(),
+    /// Builds the indexes of the commands stored before they had stable indexes, see [crate::migrations].
+    pub fn build_indexes(&mut self) {
+        let commands: Vec<DeviceCommand> = self
+            .scheduled_commands
+            .iter()
+            .chain(self.running_commands.iter())
+            .chain(self.finished_commands.iter())
+            .map(|(_, c)| c)
+            .collect();
+        for command in commands.iter() {
+            self.index_command(command);
+
+            match command.status {
+                CommandStatus::Scheduled => self.index_scheduled_command(command),
+                CommandStatus::Completed | CommandStatus::Failed(_) | CommandStatus::Cancelled => {
+                    self.finished_by_timestamp
+                        .insert(Reverse((command.get_finished_timestamp(), command.id)), ());
+                }
+                CommandStatus::Running => {}
+            }
         }
     }
+
+    fn index_command(&mut self, c: &DeviceCommand) {
+        self.commands_by_device
+            .insert(IndexKey(c.device_url.clone(), c.id), ());
+        self.commands_by_sender.insert(IndexKey(c.sender, c.id), ());
+    }
+
+    fn unindex_command(&mut self, c: &DeviceCommand) {
+        self.commands_by_device
+            .remove(&IndexKey(c.device_url.clone(), c.id));
+        self.commands_by_sender.remove(&IndexKey(c.sender, c.id));
+    }
+
+    fn index_scheduled_command(&mut self, c: &DeviceCommand) {
+        self.commands_by_timestamp
+            .insert((c.schedule_timestamp, c.id), ());
+        self.settings
+            .queues
+            .entry(c.device_url.clone())
+            .or_default();
+        self.queued_commands.insert(
+            IndexKey((c.device_url.clone(), c.schedule_timestamp), c.id),
+            (),
+        );
+        self.scheduled_by_sender
+            .insert(IndexKey(c.sender, c.id), ());
+    }
+
+    fn unindex_scheduled_command(&mut self, c: &DeviceCommand) {
+        self.commands_by_timestamp
+            .remove(&(c.schedule_timestamp, c.id));
+        self.queued_commands.remove(&IndexKey(
+            (c.device_url.clone(), c.schedule_timestamp),
+            c.id,
+        ));
+        self.scheduled_by_sender.remove(&IndexKey(c.sender, c.id));
+    }
 }
 
-async fn execute_command(command: &DeviceCommand) -> DeviceCommand {
-    print(format!("Executing command: {command:?}"));
+/// The maximum number of scheduled commands in a [CommandsView].
+const MAX_SCHEDULED_PAGE_SIZE: u32 = 100;
+/// The number of finished commands in a [CommandsView], see [HistoryQuery] for the others.
+const RECENTLY_FINISHED_COUNT: usize = 10;
 
-    let mut command_mut = command.clone();
-    command_mut.status = CommandStatus::Running;
+/// The position of a page of the scheduled commands.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct CommandsQuery {
+    /// Only the scheduled commands after this position, as returned in [CommandsView::next_cursor].
+    pub cursor: Option<ScheduleCursor>,
+    /// The maximum number of scheduled commands to return, up to 100.
+    pub limit: Option<u32>,
+}
+
+/// The position of a scheduled command in the queue, which is ordered by schedule timestamp.
+///
+/// The id breaks the ties between the commands scheduled at the same time.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize, Serialize,
+)]
+pub struct ScheduleCursor {
+    pub schedule_timestamp: u64,
+    pub id: CommandId,
+}
+
+/// The commands as returned to the frontend.
+#[derive(CandidType, Serialize)]
+pub struct CommandsView {
+    /// A page of the scheduled commands, ordered by schedule timestamp.
+    pub scheduled_commands: Vec<(CommandId, DeviceCommandView)>,
+    pub running_commands: Vec<(CommandId, DeviceCommandView)>,
+    /// The most recently finished commands, from the oldest to the newest.
+    pub finished_commands: Vec<(CommandId, DeviceCommandView)>,
+    /// The cursor to get the next page of the scheduled commands with, if there are more.
+    pub next_cursor: Option<ScheduleCursor>,
+}
 
+async fn execute_http_request(http_arguments: CommandHttpArguments) -> RequestOutcome {
     // execute the HTTPS outcall
     let request = CanisterHttpRequestArgument {
-        url: command_mut.clone().http_arguments.url,
-        method: command_mut.clone().http_arguments.method,
-        body: command_mut.clone().http_arguments.body,
+        url: http_arguments.url,
+        method: http_arguments.method,
+        body: http_arguments.body,
         max_response_bytes: Some(2048), // 2KB
         transform: Some(TransformContext::from_name(
             String::from("transform_device_response"),
             vec![],
         )),
-        headers: command_mut.clone().http_arguments.headers,
+        headers: http_arguments.headers,
     };
 
     // send the HTTP request to the device
@@ -141,74 +1376,740 @@ async fn execute_command(command: &DeviceCommand) -> DeviceCommand {
             // needed just to avoid clippy warnings
             #[allow(clippy::cmp_owned)]
             if response.status >= Nat::from(200) && response.status < Nat::from(400) {
-                command_mut.status = CommandStatus::Completed;
-            } else if response.status == Nat::from(401) {
-                // this is the case when the access key is not valid
-                print("Access key is not valid.");
-                // let's set it to None, so that the next time we'll try to get a new one
-                STATE.with(|s| {
-                    let mut state = s.borrow_mut();
-                    state.last_valid_access_key = None;
-                });
-                command_mut.status =
-                    CommandStatus::Failed(String::from("Access key is not valid."));
+                RequestOutcome::Success
             } else {
-                command_mut.status =
-                    CommandStatus::Failed(format!("HTTP status: {}", response.status));
+                if response.status == Nat::from(401) {
+                    // this is the case when the access key is not valid
+                    log("Access key is not valid.");
+                    // let's set it to None, so that the next time we'll try to get a new one
+                    STATE.with(|s| {
+                        let mut state = s.borrow_mut();
+                        state.last_valid_access_key = None;
+                    });
+                }
+
+                RequestOutcome::HttpError(response.status.0.try_into().unwrap_or(u16::MAX))
             }
         }
         Err((r, m)) => {
-            print(format!(
+            log(format!(
                 "The http_request resulted into error. RejectionCode: {r:?}, Error: {m}"
             ));
 
-            command_mut.status = CommandStatus::Failed(format!("RejectionCode: {r:?}, Error: {m}"));
+            RequestOutcome::Rejected(format!("RejectionCode: {r:?}, Error: {m}"))
+        }
+    }
+}
+
+/// Replaces the access key headers of the command requests with the ones of a new access key.
+async fn refresh_request_headers(command: &mut DeviceCommand) -> Result<(), GenericError> {
+    // commands sent before they recorded the environment use the current one of the device
+    let environment_uid = command
+        .environment_uid
+        .clone()
+        .or_else(|| {
+            STATE.with(|s| {
+                s.borrow()
+                    .access_control
+                    .get_device_environment(&command.device_url)
+            })
+        })
+        .ok_or_else(|| format!("Environment of device {} not found", command.device_url))?;
+    let headers = get_device_request_headers(&environment_uid, &command.device_url).await?;
+
+    for http_arguments in command.http_arguments.iter_mut() {
+        let content_type = http_arguments
+            .headers
+            .iter()
+            .find(|h| h.name == "Content-Type")
+            .cloned();
+
+        http_arguments.headers = headers.clone();
+        http_arguments.headers.extend(content_type);
+    }
+
+    Ok(())
+}
+
+/// Executes the command, returning it with the updated status.
+///
+/// If the command fails and the retry policy allows it, it's returned as [CommandStatus::Scheduled].
+async fn execute_command(command: &DeviceCommand) -> DeviceCommand {
+    log(format!("Executing command: {command:?}"));
+
+    let mut command_mut = command.clone();
+
+    let mut access_key_refreshed = false;
+    let mut outcome = RequestOutcome::Success;
+    let mut i = 0;
+
+    // send the HTTP requests to the device, stopping at the first one that fails
+    while i < command_mut.http_arguments.len() {
+        outcome = execute_http_request(command_mut.http_arguments[i].clone()).await;
+
+        match outcome {
+            RequestOutcome::Success => i += 1,
+            // the access key has expired, retry immediately with a new one
+            RequestOutcome::HttpError(401) if !access_key_refreshed => {
+                access_key_refreshed = true;
+                if let Err(e) = refresh_request_headers(&mut command_mut).await {
+                    outcome = RequestOutcome::Rejected(e);
+                    break;
+                }
+            }
+            _ => break,
+        }
+    }
+
+    command_mut.status = match outcome.get_error() {
+        None => CommandStatus::Completed,
+        Some(error) => {
+            command_mut.last_error = Some(error.clone());
+
+            if command_mut.retry_policy.is_retryable(&outcome)
+                && command_mut.attempts < command_mut.retry_policy.max_attempts
+            {
+                CommandStatus::Scheduled
+            } else {
+                CommandStatus::Failed(error)
+            }
         }
     };
 
-    print(format!(
-        "Command executed: {:?}",
-        command_mut.schedule_timestamp
+    log(format!(
+        "Command executed: {:?}, status: {:?}",
+        command_mut.id, command_mut.status
     ));
 
     command_mut
 }
 
-pub fn commands_interval_callback() {
-    ic_cdk::spawn(async move {
-        let commands_to_run = STATE.with(|s| {
-            let state = s.borrow_mut();
-            state.device_commands.get_commands_to_run()
-        });
+/// Arms a one-shot timer for the next time the scheduler has something to do,
+/// replacing the one armed before. No timer is armed when there's nothing to do.
+///
+/// Must be called every time the commands change.
+pub fn arm_commands_timer() {
+    let next_timestamp = STATE.with(|s| {
+        let state = s.borrow();
+        state.device_commands.get_next_wakeup_timestamp()
+    });
 
-        for command in commands_to_run {
-            STATE.with(|s| {
+    COMMANDS_TIMER.with(|t| {
+        let mut timer = t.borrow_mut();
+
+        if let (Some((_, armed_timestamp)), Some(next_timestamp)) = (*timer, next_timestamp) {
+            if armed_timestamp == next_timestamp {
+                return;
+            }
+        }
+
+        if let Some((timer_id, _)) = timer.take() {
+            clear_timer(timer_id);
+        }
+
+        if let Some(next_timestamp) = next_timestamp {
+            let delay = Duration::from_nanos(next_timestamp.saturating_sub(time()));
+            *timer = Some((set_timer(delay, commands_timer_callback), next_timestamp));
+        }
+    });
+}
+
+fn commands_timer_callback() {
+    // the timer has fired, it can't be cleared anymore
+    COMMANDS_TIMER.with(|t| t.borrow_mut().take());
+
+    execute_due_commands();
+
+    arm_commands_timer();
+}
+
+fn execute_due_commands() {
+    let commands_to_run = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let now = time();
+        state
+            .device_commands
+            .recover_running_commands(now.saturating_sub(RUNNING_COMMAND_TIMEOUT), now);
+        // the finished commands also expire while no command finishes
+        state.device_commands.prune_history(now);
+        state.device_commands.claim_commands_to_run(now)
+    });
+
+    // commands for different devices are executed concurrently,
+    // while the commands for the same device are executed one at a time, in order
+    for (device_url, claim) in commands_to_run {
+        ic_cdk::spawn(async move {
+            let command = STATE.with(|s| {
                 let mut state = s.borrow_mut();
-                state
-                    .device_commands
-                    .scheduled_commands
-                    .remove(&command.schedule_timestamp);
-
-                state
-                    .device_commands
-                    .running_commands
-                    .insert(command.schedule_timestamp, command.clone());
+                state.device_commands.start_command(&claim, time())
             });
 
-            let executed_command = execute_command(&command).await;
+            // the command may have been cancelled or rescheduled in the meantime,
+            // in which case it's not claimed anymore
+            if let Some(command) = command {
+                let executed_command = execute_command(&command).await;
+
+                STATE.with(|s| {
+                    let mut state = s.borrow_mut();
+                    match executed_command.status {
+                        CommandStatus::Scheduled => state
+                            .device_commands
+                            .retry_command(executed_command, time()),
+                        _ => state
+                            .device_commands
+                            .finish_command(executed_command, time()),
+                    }
+                });
+            }
 
             STATE.with(|s| {
                 let mut state = s.borrow_mut();
-                state
-                    .device_commands
-                    .running_commands
-                    .remove(&executed_command.schedule_timestamp);
-
-                state
-                    .device_commands
-                    .finished_commands
-                    .insert(executed_command.schedule_timestamp, executed_command)
+                state.device_commands.release_lease(&device_url, &claim)
             });
+
+            arm_commands_timer();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE_URL: &str = "https://light.example.com";
+    const OTHER_DEVICE_URL: &str = "https://other-light.example.com";
+    const SECOND: u64 = 1_000_000_000;
+
+    fn new_command(device_url: &str, sender: Principal) -> DeviceCommand {
+        DeviceCommand::new(
+            "environment".to_string(),
+            device_url.to_string(),
+            vec![],
+            0,
+            sender,
+            None,
+            RetryPolicy::default(),
+        )
+    }
+
+    fn schedule_command(commands: &mut DeviceCommands, timestamp: u64, now: u64) -> CommandId {
+        schedule_device_command(commands, DEVICE_URL, timestamp, now)
+    }
+
+    fn schedule_device_command(
+        commands: &mut DeviceCommands,
+        device_url: &str,
+        timestamp: u64,
+        now: u64,
+    ) -> CommandId {
+        commands
+            .schedule_command(
+                new_command(device_url, Principal::anonymous()),
+                CommandSchedule::At(timestamp),
+                now,
+            )
+            .unwrap()
+            .command_id
+    }
+
+    fn claim(commands: &mut DeviceCommands, now: u64) -> Option<CommandClaim> {
+        commands.claim_commands_to_run(now).get(DEVICE_URL).cloned()
+    }
+
+    fn claimed_id(commands: &mut DeviceCommands, now: u64) -> Option<CommandId> {
+        claim(commands, now).map(|claim| claim.command_id)
+    }
+
+    fn complete(
+        commands: &mut DeviceCommands,
+        claim: &CommandClaim,
+        mut command: DeviceCommand,
+        now: u64,
+    ) {
+        command.status = CommandStatus::Completed;
+        commands.finish_command(command, now);
+        commands.release_lease(&DEVICE_URL.to_string(), claim);
+    }
+
+    #[test]
+    fn rejects_retry_policies_that_retry_without_bounds() {
+        assert!(RetryPolicy::default().validate().is_ok());
+        assert!(RetryPolicy {
+            max_attempts: u32::MAX,
+            ..Default::default()
         }
-    });
+        .validate()
+        .is_err());
+        assert!(RetryPolicy {
+            initial_backoff: 0,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(RetryPolicy {
+            max_backoff: 0,
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_schedules_beyond_the_horizon() {
+        let now = 100 * SECOND;
+        for schedule in [
+            CommandSchedule::At(u64::MAX),
+            CommandSchedule::After(u64::MAX),
+            CommandSchedule::After(MAX_SCHEDULE_HORIZON + 1),
+        ] {
+            assert!(schedule.get_requested_timestamp(now).is_err());
+        }
+        assert_eq!(
+            CommandSchedule::After(MAX_SCHEDULE_HORIZON).get_requested_timestamp(now),
+            Ok(now + MAX_SCHEDULE_HORIZON)
+        );
+
+        // from 2025-03-01, the next leap day is 2028-02-29
+        let now = 20_148 * 24 * 60 * 60 * SECOND;
+        assert_eq!(
+            CommandSchedule::Cron("0 0 29 2 *".to_string()).get_requested_timestamp(now),
+            Ok(21_243 * 24 * 60 * 60 * SECOND)
+        );
+    }
+
+    #[test]
+    fn limits_the_scheduled_commands_of_each_sender() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        for i in 0..MAX_SCHEDULED_COMMANDS_PER_SENDER as u64 {
+            schedule_command(&mut commands, i * COMMANDS_INTERVAL, 0);
+        }
+
+        let err = commands
+            .schedule_command(
+                new_command(DEVICE_URL, Principal::anonymous()),
+                CommandSchedule::At(0),
+                0,
+            )
+            .unwrap_err();
+        assert!(err.starts_with("Can't schedule more than"), "{err}");
+
+        // the other senders can still schedule their commands
+        let other_sender = Principal::management_canister();
+        assert!(commands
+            .schedule_command(
+                new_command(DEVICE_URL, other_sender),
+                CommandSchedule::At(0),
+                0
+            )
+            .is_ok());
+
+        // the sender can schedule again once one of their commands has been cancelled
+        commands.cancel_command(0, 0).unwrap();
+        assert!(commands
+            .schedule_command(
+                new_command(DEVICE_URL, Principal::anonymous()),
+                CommandSchedule::At(0),
+                0
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn finds_free_slots_near_the_end_of_time() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let device_url = DEVICE_URL.to_string();
+        commands.settings.queues.insert(
+            device_url.clone(),
+            DeviceQueue {
+                last_started_timestamp: u64::MAX - SECOND,
+                ..Default::default()
+            },
+        );
+        assert_eq!(commands.get_free_slot(&device_url, 0), u64::MAX);
+
+        commands
+            .queued_commands
+            .insert(IndexKey((device_url.clone(), u64::MAX - SECOND), 0), ());
+        assert_eq!(
+            commands.get_free_slot(&device_url, u64::MAX - 2 * SECOND),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn claims_a_command_once_while_its_outcall_is_in_flight() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let id = schedule_command(&mut commands, 100 * SECOND, 0);
+
+        let first_claim = claim(&mut commands, 100 * SECOND).unwrap();
+        assert_eq!(first_claim.command_id, id);
+        let command = commands.start_command(&first_claim, 100 * SECOND).unwrap();
+        assert_eq!(command.attempts, 1);
+
+        // the outcall is slow, the next wake-ups must not claim or start the command again
+        assert_eq!(claim(&mut commands, 200 * SECOND), None);
+        assert!(commands.start_command(&first_claim, 200 * SECOND).is_none());
+
+        complete(&mut commands, &first_claim, command, 300 * SECOND);
+        assert!(matches!(
+            commands.get_command(id).unwrap().status,
+            CommandStatus::Completed
+        ));
+        assert_eq!(claimed_id(&mut commands, 300 * SECOND), None);
+    }
+
+    #[test]
+    fn waits_the_device_interval_between_commands_that_piled_up() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let first_id = schedule_command(&mut commands, 100 * SECOND, 0);
+        let second_id = schedule_command(&mut commands, 100 * SECOND, 0);
+
+        // both commands are due when the slow first one completes
+        let now = 200 * SECOND;
+        let first_claim = claim(&mut commands, now).unwrap();
+        assert_eq!(first_claim.command_id, first_id);
+        let command = commands.start_command(&first_claim, now).unwrap();
+        complete(&mut commands, &first_claim, command, now + SECOND);
+
+        assert_eq!(claimed_id(&mut commands, now + SECOND), None);
+        assert_eq!(
+            commands.get_next_wakeup_timestamp(),
+            Some(now + COMMANDS_INTERVAL)
+        );
+        assert_eq!(
+            claimed_id(&mut commands, now + COMMANDS_INTERVAL),
+            Some(second_id)
+        );
+    }
+
+    #[test]
+    fn claims_the_commands_of_different_devices_in_the_same_wake_up() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let other_interval = 2 * COMMANDS_INTERVAL;
+        commands.set_device_interval(OTHER_DEVICE_URL.to_string(), other_interval);
+        let first_id = schedule_command(&mut commands, 100 * SECOND, 0);
+        let second_id = schedule_command(&mut commands, 100 * SECOND, 0);
+        let other_first_id =
+            schedule_device_command(&mut commands, OTHER_DEVICE_URL, 100 * SECOND, 0);
+        let other_second_id =
+            schedule_device_command(&mut commands, OTHER_DEVICE_URL, 100 * SECOND, 0);
+
+        let now = 100 * SECOND;
+        let claims = commands.claim_commands_to_run(now);
+        assert_eq!(claims.len(), 2);
+        let first_claim = &claims[DEVICE_URL];
+        let other_claim = &claims[OTHER_DEVICE_URL];
+        assert_eq!(first_claim.command_id, first_id);
+        assert_eq!(other_claim.command_id, other_first_id);
+
+        let command = commands.start_command(first_claim, now).unwrap();
+        complete(&mut commands, first_claim, command, now);
+        let mut other_command = commands.start_command(other_claim, now).unwrap();
+        other_command.status = CommandStatus::Completed;
+        commands.finish_command(other_command, now);
+        commands.release_lease(&OTHER_DEVICE_URL.to_string(), other_claim);
+
+        // each device waits its own interval before its next command
+        let claims = commands.claim_commands_to_run(now + COMMANDS_INTERVAL);
+        assert_eq!(claims.len(), 1);
+        assert_eq!(claims[DEVICE_URL].command_id, second_id);
+
+        let claims = commands.claim_commands_to_run(now + other_interval);
+        assert_eq!(claims.len(), 1);
+        assert_eq!(claims[OTHER_DEVICE_URL].command_id, other_second_id);
+    }
+
+    #[test]
+    fn claims_the_commands_again_when_the_lease_expires() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let id = schedule_command(&mut commands, 100 * SECOND, 0);
+
+        // the execution that claimed the command never starts it, e.g. because it trapped
+        let expired_claim = claim(&mut commands, 100 * SECOND).unwrap();
+        assert_eq!(expired_claim.command_id, id);
+        assert_eq!(
+            claimed_id(&mut commands, 100 * SECOND + RUNNING_COMMAND_TIMEOUT - 1),
+            None
+        );
+        let new_claim = claim(&mut commands, 100 * SECOND + RUNNING_COMMAND_TIMEOUT).unwrap();
+        assert_eq!(new_claim.command_id, id);
+        assert!(commands
+            .start_command(&expired_claim, 100 * SECOND + RUNNING_COMMAND_TIMEOUT)
+            .is_none());
+        assert!(commands
+            .start_command(&new_claim, 100 * SECOND + RUNNING_COMMAND_TIMEOUT)
+            .is_some());
+    }
+
+    #[test]
+    fn ignores_the_outcome_of_a_recovered_command() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let id = schedule_command(&mut commands, 100 * SECOND, 0);
+
+        let first_claim = claim(&mut commands, 100 * SECOND).unwrap();
+        let command = commands.start_command(&first_claim, 100 * SECOND).unwrap();
+
+        // the outcall takes longer than the timeout, so the command is retried
+        let now = 100 * SECOND + RUNNING_COMMAND_TIMEOUT;
+        commands.recover_running_commands(now - RUNNING_COMMAND_TIMEOUT, now);
+        let recovered = commands.get_command(id).unwrap();
+        assert!(matches!(recovered.status, CommandStatus::Scheduled));
+        assert!(recovered.schedule_timestamp > now);
+
+        // the late outcome must not finish the retried command
+        complete(&mut commands, &first_claim, command, now + SECOND);
+        assert!(matches!(
+            commands.get_command(id).unwrap().status,
+            CommandStatus::Scheduled
+        ));
+    }
+
+    #[test]
+    fn ignores_the_outcome_of_a_recovered_command_started_again() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let id = schedule_command(&mut commands, 100 * SECOND, 0);
+
+        let first_claim = claim(&mut commands, 100 * SECOND).unwrap();
+        let first_attempt = commands.start_command(&first_claim, 100 * SECOND).unwrap();
+
+        // the first execution outlives the timeout, so the command is recovered and started again
+        let now = 100 * SECOND + RUNNING_COMMAND_TIMEOUT;
+        commands.recover_running_commands(now - RUNNING_COMMAND_TIMEOUT, now);
+        let retry_timestamp = commands.get_command(id).unwrap().schedule_timestamp;
+        let second_claim = claim(&mut commands, retry_timestamp).unwrap();
+        assert_eq!(second_claim.command_id, id);
+        let second_attempt = commands
+            .start_command(&second_claim, retry_timestamp)
+            .unwrap();
+
+        // the late outcome of the first attempt must not finish the second one, nor release its lease
+        complete(
+            &mut commands,
+            &first_claim,
+            first_attempt,
+            retry_timestamp + SECOND,
+        );
+        let command = commands.get_command(id).unwrap();
+        assert!(matches!(command.status, CommandStatus::Running));
+        assert_eq!(command.attempts, 2);
+        assert!(commands
+            .claim_commands_to_run(retry_timestamp + SECOND)
+            .is_empty());
+
+        complete(
+            &mut commands,
+            &second_claim,
+            second_attempt,
+            retry_timestamp + 2 * SECOND,
+        );
+        assert!(matches!(
+            commands.get_command(id).unwrap().status,
+            CommandStatus::Completed
+        ));
+    }
+
+    #[test]
+    fn doesnt_start_a_claimed_command_rescheduled_in_the_meantime() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let id = schedule_command(&mut commands, 100 * SECOND, 0);
+
+        let first_claim = claim(&mut commands, 100 * SECOND).unwrap();
+        commands
+            .reschedule_command(id, 1_000 * SECOND, 100 * SECOND)
+            .unwrap();
+        assert!(commands.start_command(&first_claim, 100 * SECOND).is_none());
+        commands.release_lease(&DEVICE_URL.to_string(), &first_claim);
+
+        assert_eq!(claim(&mut commands, 999 * SECOND), None);
+        let second_claim = claim(&mut commands, 1_000 * SECOND).unwrap();
+        assert_eq!(second_claim.command_id, id);
+        assert!(commands
+            .start_command(&second_claim, 1_000 * SECOND)
+            .is_some());
+    }
+
+    #[test]
+    fn doesnt_start_a_claimed_command_cancelled_in_the_meantime() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let id = schedule_command(&mut commands, 100 * SECOND, 0);
+
+        let first_claim = claim(&mut commands, 100 * SECOND).unwrap();
+        commands.cancel_command(id, 100 * SECOND).unwrap();
+        assert!(commands.start_command(&first_claim, 100 * SECOND).is_none());
+        assert!(matches!(
+            commands.get_command(id).unwrap().status,
+            CommandStatus::Cancelled
+        ));
+    }
+
+    #[test]
+    fn pages_the_scheduled_commands_by_schedule_timestamp() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let third_id = schedule_command(&mut commands, 300 * SECOND, 0);
+        let first_id = schedule_command(&mut commands, 100 * SECOND, 0);
+        let second_id = schedule_command(&mut commands, 200 * SECOND, 0);
+        let finished_id = schedule_command(&mut commands, 400 * SECOND, 0);
+        commands.cancel_command(finished_id, 10 * SECOND).unwrap();
+
+        let ids = |commands: &[(CommandId, DeviceCommandView)]| {
+            commands.iter().map(|(id, _)| *id).collect::<Vec<_>>()
+        };
+
+        let query = CommandsQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let view = commands.get_commands_view(&query, |_| true);
+        assert_eq!(ids(&view.scheduled_commands), vec![first_id, second_id]);
+        assert_eq!(ids(&view.finished_commands), vec![finished_id]);
+
+        let view = commands.get_commands_view(
+            &CommandsQuery {
+                cursor: view.next_cursor,
+                ..query
+            },
+            |_| true,
+        );
+        assert_eq!(ids(&view.scheduled_commands), vec![third_id]);
+        assert!(view.next_cursor.is_none());
+
+        // the commands the caller can't view are skipped
+        let view = commands.get_commands_view(&query, |c| c.id != first_id);
+        assert_eq!(ids(&view.scheduled_commands), vec![second_id, third_id]);
+        assert!(view.next_cursor.is_none());
+    }
+
+    #[test]
+    fn keeps_the_commands_of_different_devices_with_the_same_timestamps() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let id = schedule_command(&mut commands, 100 * SECOND, 0);
+        let other_id = schedule_device_command(&mut commands, OTHER_DEVICE_URL, 100 * SECOND, 0);
+        assert_ne!(id, other_id);
+
+        let view = commands.get_commands_view(&CommandsQuery::default(), |_| true);
+        let scheduled: Vec<_> = view
+            .scheduled_commands
+            .iter()
+            .map(|(id, command)| (*id, command.schedule_timestamp))
+            .collect();
+        assert_eq!(
+            scheduled,
+            vec![(id, 100 * SECOND), (other_id, 100 * SECOND)]
+        );
+
+        // they also finish at the same time
+        commands.cancel_command(id, 200 * SECOND).unwrap();
+        commands.cancel_command(other_id, 200 * SECOND).unwrap();
+
+        let page = commands.get_history(&HistoryQuery::default(), |_| true);
+        let finished: Vec<_> = page
+            .commands
+            .iter()
+            .map(|command| (command.id, command.device_url.as_str()))
+            .collect();
+        assert_eq!(
+            finished,
+            vec![(other_id, OTHER_DEVICE_URL), (id, DEVICE_URL)]
+        );
+    }
+
+    #[test]
+    fn wakes_up_to_remove_the_expired_history() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        commands.set_history_retention(
+            HistoryRetention {
+                max_commands: 10,
+                max_age: 60 * SECOND,
+            },
+            0,
+        );
+        let first_id = schedule_command(&mut commands, 100 * SECOND, 0);
+        let second_id = schedule_command(&mut commands, 100 * SECOND, 0);
+        commands.cancel_command(first_id, 10 * SECOND).unwrap();
+        commands.cancel_command(second_id, 20 * SECOND).unwrap();
+
+        // the canister is idle, but the oldest finished command expires
+        assert_eq!(commands.get_next_wakeup_timestamp(), Some(70 * SECOND));
+
+        commands.prune_history(70 * SECOND);
+        assert!(commands.get_command(first_id).is_none());
+        assert!(commands.get_command(second_id).is_some());
+        assert_eq!(commands.get_next_wakeup_timestamp(), Some(80 * SECOND));
+
+        commands.prune_history(80 * SECOND);
+        assert!(commands.get_command(second_id).is_none());
+        assert_eq!(commands.get_next_wakeup_timestamp(), None);
+    }
+
+    #[test]
+    fn pages_the_history_by_finish_timestamp() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let first_id = schedule_command(&mut commands, 100 * SECOND, 0);
+        let second_id = schedule_command(&mut commands, 100 * SECOND, 0);
+        let third_id = schedule_command(&mut commands, 100 * SECOND, 0);
+
+        // the commands finish in a different order than they have been scheduled
+        commands.cancel_command(third_id, 10 * SECOND).unwrap();
+        commands.cancel_command(first_id, 20 * SECOND).unwrap();
+        commands.cancel_command(second_id, 30 * SECOND).unwrap();
+
+        let ids = |page: &HistoryPage| page.commands.iter().map(|c| c.id).collect::<Vec<_>>();
+
+        let query = HistoryQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let page = commands.get_history(&query, |_| true);
+        assert_eq!(ids(&page), vec![second_id, first_id]);
+
+        let page = commands.get_history(
+            &HistoryQuery {
+                cursor: page.next_cursor,
+                ..query
+            },
+            |_| true,
+        );
+        assert_eq!(ids(&page), vec![third_id]);
+        assert!(page.next_cursor.is_none());
+
+        let page = commands.get_history(
+            &HistoryQuery {
+                from_timestamp: Some(15 * SECOND),
+                to_timestamp: Some(30 * SECOND),
+                ..Default::default()
+            },
+            |_| true,
+        );
+        assert_eq!(ids(&page), vec![first_id]);
+
+        let page = commands.get_history(
+            &HistoryQuery {
+                from_timestamp: Some(30 * SECOND),
+                to_timestamp: Some(10 * SECOND),
+                ..Default::default()
+            },
+            |_| true,
+        );
+        assert!(page.commands.is_empty());
+    }
+
+    #[test]
+    fn cant_cancel_or_reschedule_a_running_command() {
+        let mut commands = DeviceCommands::init(CommandsSettings::default());
+        let id = schedule_command(&mut commands, 100 * SECOND, 0);
+
+        let first_claim = claim(&mut commands, 100 * SECOND).unwrap();
+        let command = commands.start_command(&first_claim, 100 * SECOND).unwrap();
+
+        assert!(commands.cancel_command(id, 101 * SECOND).is_err());
+        assert!(commands
+            .reschedule_command(id, 1_000 * SECOND, 101 * SECOND)
+            .is_err());
+
+        complete(&mut commands, &first_claim, command, 102 * SECOND);
+        assert!(matches!(
+            commands.get_command(id).unwrap().status,
+            CommandStatus::Completed
+        ));
+    }
 }
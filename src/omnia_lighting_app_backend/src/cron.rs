@@ -0,0 +1,334 @@
+use std::{collections::BTreeSet, str::FromStr};
+
+use crate::rdf::GenericError;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const SECONDS_PER_MINUTE: u64 = 60;
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// How many days ahead we look for the next occurrence, before giving up.
+/// Covers the expressions that match only on leap days.
+const MAX_LOOKAHEAD_DAYS: u64 = 366 * 8;
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A standard 5-field cron expression (`minute hour day-of-month month day-of-week`), evaluated in UTC.
+///
+/// Each field supports `*`, single values, ranges (`1-5`), lists (`1,3,5`) and steps (`*/15`, `0-30/10`).
+/// Months and days of the week can also be written with their English three-letter names (`jan`, `mon`).
+///
+/// Example: `0 19 * * mon-fri` runs at 19:00 UTC from Monday to Friday.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: BTreeSet<u8>,
+    hours: BTreeSet<u8>,
+    days_of_month: BTreeSet<u8>,
+    months: BTreeSet<u8>,
+    days_of_week: BTreeSet<u8>,
+    /// As in Vixie cron, if both day fields are restricted, a day matches if either of them matches.
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = GenericError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "Invalid cron expression {s}: expected 5 fields, got {}",
+                fields.len()
+            ));
+        }
+
+        let mut days_of_week = parse_field(fields[4], 0, 7, &WEEKDAY_NAMES)?;
+        // both 0 and 7 are Sunday
+        if days_of_week.remove(&7) {
+            days_of_week.insert(0);
+        }
+
+        Ok(Self {
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days_of_month: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, &MONTH_NAMES)?,
+            days_of_week,
+            days_of_month_restricted: fields[2] != "*",
+            days_of_week_restricted: fields[4] != "*",
+        })
+    }
+}
+
+impl CronSchedule {
+    /// Returns the first timestamp (in nanoseconds) strictly after the given one that matches the expression.
+    pub fn next_after(&self, timestamp: u64) -> Option<u64> {
+        // cron has a minute resolution, so we start from the next minute
+        let start_minute = timestamp / NANOS_PER_SECOND / SECONDS_PER_MINUTE + 1;
+        let start_day = start_minute * SECONDS_PER_MINUTE / SECONDS_PER_DAY;
+
+        for day in start_day..start_day + MAX_LOOKAHEAD_DAYS {
+            if !self.matches_day(day) {
+                continue;
+            }
+
+            for hour in self.hours.iter() {
+                for minute in self.minutes.iter() {
+                    let minutes = (day * SECONDS_PER_DAY) / SECONDS_PER_MINUTE
+                        + *hour as u64 * 60
+                        + *minute as u64;
+                    if minutes >= start_minute {
+                        return Some(minutes * SECONDS_PER_MINUTE * NANOS_PER_SECOND);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    fn matches_day(&self, days_since_epoch: u64) -> bool {
+        let (_, month, day) = civil_from_days(days_since_epoch);
+        if !self.months.contains(&month) {
+            return false;
+        }
+
+        // 1970-01-01 was a Thursday
+        let weekday = ((days_since_epoch + 4) % 7) as u8;
+        let day_of_month_matches = self.days_of_month.contains(&day);
+        let day_of_week_matches = self.days_of_week.contains(&weekday);
+
+        if self.days_of_month_restricted && self.days_of_week_restricted {
+            day_of_month_matches || day_of_week_matches
+        } else {
+            day_of_month_matches && day_of_week_matches
+        }
+    }
+}
+
+fn parse_value(value: &str, names: &[&str]) -> Option<u8> {
+    value.parse::<u8>().ok().or_else(|| {
+        let value = value.to_lowercase();
+        let offset = if names.len() == 12 { 1 } else { 0 };
+        names
+            .iter()
+            .position(|n| *n == value)
+            .map(|i| i as u8 + offset)
+    })
+}
+
+fn parse_field(
+    field: &str,
+    min: u8,
+    max: u8,
+    names: &[&str],
+) -> Result<BTreeSet<u8>, GenericError> {
+    let invalid = || format!("Invalid cron field: {field}");
+    let mut values = BTreeSet::new();
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u8>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(invalid());
+        }
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                parse_value(start, names).ok_or_else(invalid)?,
+                parse_value(end, names).ok_or_else(invalid)?,
+            )
+        } else {
+            let value = parse_value(range, names).ok_or_else(invalid)?;
+            // `5/10` means from 5 to the maximum, every 10
+            (value, if part.contains('/') { max } else { value })
+        };
+
+        if start < min || end > max || start > end {
+            return Err(format!(
+                "Invalid cron field: {field}, values must be between {min} and {max}"
+            ));
+        }
+
+        values.extend((start..=end).step_by(step as usize));
+    }
+
+    Ok(values)
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) civil date.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: u64) -> (u64, u8, u8) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NANOS_PER_DAY: u64 = SECONDS_PER_DAY * NANOS_PER_SECOND;
+    const NANOS_PER_MINUTE: u64 = SECONDS_PER_MINUTE * NANOS_PER_SECOND;
+
+    // days since the Unix epoch
+    const THU_1970_01_01: u64 = 0;
+    const TUE_2000_02_29: u64 = 11_016;
+    const THU_2024_02_29: u64 = 19_782;
+    const TUE_2024_12_31: u64 = 20_088;
+    const SAT_2025_01_11: u64 = 20_099;
+    const SAT_2025_03_01: u64 = 20_148;
+    const TUE_2028_02_29: u64 = 21_243;
+    const MON_2100_03_01: u64 = 47_541;
+
+    fn cron(expression: &str) -> CronSchedule {
+        CronSchedule::from_str(expression).unwrap()
+    }
+
+    fn timestamp(day: u64, hour: u64, minute: u64) -> u64 {
+        day * NANOS_PER_DAY + (hour * 60 + minute) * NANOS_PER_MINUTE
+    }
+
+    #[test]
+    fn parses_ranges_steps_and_names() {
+        let schedule = cron("0-30/10 1,2 */10 jan-MAR mon-fri");
+        assert_eq!(schedule.minutes, BTreeSet::from([0, 10, 20, 30]));
+        assert_eq!(schedule.hours, BTreeSet::from([1, 2]));
+        assert_eq!(schedule.days_of_month, BTreeSet::from([1, 11, 21, 31]));
+        assert_eq!(schedule.months, BTreeSet::from([1, 2, 3]));
+        assert_eq!(schedule.days_of_week, BTreeSet::from([1, 2, 3, 4, 5]));
+
+        // a single value with a step runs until the maximum
+        assert_eq!(cron("5/20 * * * *").minutes, BTreeSet::from([5, 25, 45]));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for expression in [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * * foo *",
+            "* * * * sunday",
+            "1-2-3 * * * *",
+        ] {
+            assert!(
+                CronSchedule::from_str(expression).is_err(),
+                "{expression} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn treats_7_as_sunday() {
+        assert_eq!(cron("0 0 * * 7"), cron("0 0 * * 0"));
+        assert_eq!(cron("0 0 * * 7"), cron("0 0 * * sun"));
+        assert_eq!(cron("0 0 * * 5-7").days_of_week, BTreeSet::from([0, 5, 6]));
+
+        // 1970-01-04 was the first Sunday
+        assert_eq!(
+            cron("0 0 * * 7").next_after(timestamp(THU_1970_01_01, 0, 0)),
+            Some(timestamp(THU_1970_01_01 + 3, 0, 0))
+        );
+    }
+
+    #[test]
+    fn matches_either_day_field_when_both_are_restricted() {
+        // from 2024-12-31, the next Friday is 2025-01-03 and the next 13th is 2025-01-13
+        let after = timestamp(TUE_2024_12_31, 12, 0);
+        assert_eq!(
+            cron("0 0 13 * fri").next_after(after),
+            Some(timestamp(TUE_2024_12_31 + 3, 0, 0))
+        );
+        assert_eq!(
+            cron("0 0 13 * *").next_after(after),
+            Some(timestamp(TUE_2024_12_31 + 13, 0, 0))
+        );
+        assert_eq!(
+            cron("0 0 * * fri").next_after(after),
+            Some(timestamp(TUE_2024_12_31 + 3, 0, 0))
+        );
+
+        // from 2025-01-11, the 13th comes before the next Friday
+        assert_eq!(
+            cron("0 0 13 * fri").next_after(timestamp(SAT_2025_01_11, 0, 0)),
+            Some(timestamp(SAT_2025_01_11 + 2, 0, 0))
+        );
+    }
+
+    #[test]
+    fn returns_the_next_minute_strictly_after() {
+        let schedule = cron("*/15 9-10 * * *");
+        assert_eq!(
+            schedule.next_after(timestamp(TUE_2024_12_31, 9, 0)),
+            Some(timestamp(TUE_2024_12_31, 9, 15))
+        );
+        assert_eq!(
+            schedule.next_after(timestamp(TUE_2024_12_31, 9, 7) + 30 * NANOS_PER_SECOND),
+            Some(timestamp(TUE_2024_12_31, 9, 15))
+        );
+        assert_eq!(
+            schedule.next_after(timestamp(TUE_2024_12_31, 10, 45)),
+            Some(timestamp(TUE_2024_12_31 + 1, 9, 0))
+        );
+    }
+
+    #[test]
+    fn finds_leap_days() {
+        let schedule = cron("30 6 29 feb *");
+        assert_eq!(
+            schedule.next_after(timestamp(SAT_2025_03_01, 0, 0)),
+            Some(timestamp(TUE_2028_02_29, 6, 30))
+        );
+        assert_eq!(
+            schedule.next_after(timestamp(THU_2024_02_29, 0, 0)),
+            Some(timestamp(THU_2024_02_29, 6, 30))
+        );
+    }
+
+    #[test]
+    fn never_matches_impossible_dates() {
+        for expression in ["0 0 30 2 *", "0 0 31 apr,jun,sep,nov *"] {
+            assert_eq!(
+                cron(expression).next_after(timestamp(TUE_2024_12_31, 0, 0)),
+                None,
+                "{expression} should never match"
+            );
+        }
+    }
+
+    #[test]
+    fn converts_days_to_civil_dates() {
+        assert_eq!(civil_from_days(THU_1970_01_01), (1970, 1, 1));
+        assert_eq!(civil_from_days(TUE_2000_02_29), (2000, 2, 29));
+        assert_eq!(civil_from_days(THU_2024_02_29), (2024, 2, 29));
+        assert_eq!(civil_from_days(TUE_2024_12_31), (2024, 12, 31));
+        assert_eq!(civil_from_days(TUE_2024_12_31 + 1), (2025, 1, 1));
+        assert_eq!(civil_from_days(SAT_2025_03_01 - 1), (2025, 2, 28));
+        // 2100 is not a leap year
+        assert_eq!(civil_from_days(MON_2100_03_01 - 1), (2100, 2, 28));
+        assert_eq!(civil_from_days(MON_2100_03_01), (2100, 3, 1));
+    }
+}
@@ -0,0 +1,450 @@
+//! The devices of each environment are fetched from the Omnia backend and cached in the state,
+//! so that they can be served by query calls.
+//!
+//! The cache is refreshed on demand with `refresh_environment` and in the background by a one-shot timer,
+//! which is armed for the next time one of the environments used recently goes stale.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+use candid::CandidType;
+use ic_cdk::api::time;
+use ic_cdk_timers::{clear_timer, set_timer, TimerId};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    rdf::{
+        query::{Expression, Pattern, SelectQuery, Term},
+        send_query, GenericError, QueryRows,
+    },
+    topology::{fetch_zones, Zones},
+    utils::log,
+    wot::{Capability, DeviceUrl, EnvironmentDevices, EnvironmentUid, Room, WotDevices},
+    STATE,
+};
+
+/// After how long (in nanoseconds) the devices of an environment are considered stale.
+pub const DEVICES_TTL: u64 = 10 * 60 * 1_000_000_000;
+/// The minimum delay (in nanoseconds) before the timer fires, so that a failing refresh is retried at most once a minute.
+const DEVICES_REFRESH_RETRY_DELAY: u64 = 60 * 1_000_000_000;
+/// After how long (in nanoseconds) without being used an environment is no longer refreshed by the timer.
+const ENVIRONMENT_IDLE_TIMEOUT: u64 = 60 * 60 * 1_000_000_000;
+
+thread_local! {
+    /// The environments being refreshed by the timer, so that a slow refresh is not started again.
+    static REFRESHING_ENVIRONMENTS: RefCell<BTreeSet<EnvironmentUid>> = const { RefCell::new(BTreeSet::new()) };
+    /// When each environment has last been used (in nanoseconds) by an update call.
+    ///
+    /// Kept on the heap, since after an upgrade the environments are refreshed again on their next use.
+    static ENVIRONMENTS_LAST_USED: RefCell<BTreeMap<EnvironmentUid, u64>> = const { RefCell::new(BTreeMap::new()) };
+    /// The timer armed for the next refresh of the stale environments, with its timestamp (in nanoseconds).
+    static DEVICES_REFRESH_TIMER: RefCell<Option<(TimerId, u64)>> = const { RefCell::new(None) };
+}
+
+/// The devices of an environment, as they were cached.
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct CachedDevices {
+    pub devices: WotDevices,
+    /// When the devices have been fetched (in nanoseconds).
+    pub fetched_at: u64,
+    /// Whether the devices are older than [DEVICES_TTL] and should be refreshed.
+    pub is_stale: bool,
+}
+
+/// A header required by a device, as returned by the discovery query.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceHeaderRow {
+    device: DeviceUrl,
+    header_name: String,
+    header_value: String,
+    /// The class of a command supported by the device, one per row.
+    command: Option<String>,
+}
+
+/// The description of a device, as returned by the metadata query.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceMetadataRow {
+    device: DeviceUrl,
+    name: Option<String>,
+    room: Option<String>,
+    room_name: Option<String>,
+    category: Option<String>,
+    manufacturer: Option<String>,
+}
+
+impl From<EnvironmentDevices> for CachedDevices {
+    fn from(environment: EnvironmentDevices) -> Self {
+        Self {
+            is_stale: is_stale(&environment, time()),
+            devices: environment.devices,
+            fetched_at: environment.fetched_at,
+        }
+    }
+}
+
+fn is_stale(environment: &EnvironmentDevices, now: u64) -> bool {
+    environment.fetched_at.saturating_add(DEVICES_TTL) <= now
+}
+
+/// Returns the devices and zones of the environment as cached the last time they have been fetched,
+/// or an error if they have never been fetched.
+pub fn get_cached_environment(
+    environment_uid: &EnvironmentUid,
+) -> Result<EnvironmentDevices, GenericError> {
+    STATE
+        .with(|state| state.borrow().environment_devices.get(environment_uid))
+        .ok_or_else(|| {
+            format!(
+                "Devices of environment {environment_uid} not fetched yet, refresh the environment"
+            )
+        })
+}
+
+/// Returns the cached devices of the environment that have all the given capabilities.
+pub fn get_cached_devices(
+    environment_uid: &EnvironmentUid,
+    capabilities: &BTreeSet<Capability>,
+) -> Result<CachedDevices, GenericError> {
+    let mut environment = get_cached_environment(environment_uid)?;
+
+    environment
+        .devices
+        .retain(|_, device| device.capabilities.is_superset(capabilities));
+
+    Ok(environment.into())
+}
+
+/// Fetches the devices of the environment from the Omnia backend and caches them.
+pub async fn refresh_environment_devices(
+    environment_uuid: Uuid,
+) -> Result<CachedDevices, GenericError> {
+    let mut devices = fetch_devices(environment_uuid).await?;
+    // the metadata only describes the devices, which can still be operated without it
+    if let Err(e) = fetch_devices_metadata(environment_uuid, &mut devices).await {
+        log(format!(
+            "Failed to fetch devices metadata of environment {environment_uuid}: {e}"
+        ));
+    }
+    // the zones are not needed to operate the devices, so an environment without a topology still works
+    let zones = fetch_zones(environment_uuid).await.unwrap_or_else(|e| {
+        log(format!(
+            "Failed to fetch zones of environment {environment_uuid}: {e}"
+        ));
+        Zones::new()
+    });
+
+    let environment = EnvironmentDevices {
+        devices,
+        fetched_at: time(),
+        zones,
+    };
+
+    // save the devices in the shared state, so that we can use them in the other methods
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+
+        // the Thing Descriptions may have changed too, they're fetched again at the next command
+        let previous_devices = state
+            .environment_devices
+            .get(&environment_uuid.to_string())
+            .map(|previous| previous.devices)
+            .unwrap_or_default();
+        for device_url in previous_devices.keys().chain(environment.devices.keys()) {
+            state.thing_descriptions.remove(device_url);
+        }
+
+        for device_url in environment.devices.keys() {
+            state
+                .access_control
+                .set_device_environment(device_url.clone(), environment_uuid.to_string());
+        }
+
+        state
+            .environment_devices
+            .insert(environment_uuid.to_string(), environment.clone());
+    });
+    arm_devices_refresh_timer();
+
+    Ok(environment.into())
+}
+
+/// Returns the devices of the environment with the headers needed to send requests to them
+/// and the commands they support among the ones we know.
+async fn fetch_devices(environment_uuid: Uuid) -> Result<WotDevices, GenericError> {
+    let known_commands = Capability::ALL
+        .iter()
+        .flat_map(|capability| capability.command_iris())
+        .map(|iri| vec![Some(Term::iri(*iri))])
+        .collect();
+    let query = SelectQuery::new(["device", "headerName", "headerValue", "command"])
+        .triple(
+            Term::from(environment_uuid),
+            Term::prefixed("bot:hasElement"),
+            Term::var("device"),
+        )
+        .triple(
+            Term::var("device"),
+            Term::prefixed("rdf:type"),
+            Term::prefixed("saref:Device"),
+        )
+        .triple(
+            Term::var("device"),
+            Term::prefixed("omnia:requiresHeader"),
+            Term::var("header"),
+        )
+        .triple(
+            Term::var("header"),
+            Term::prefixed("http:fieldName"),
+            Term::var("headerName"),
+        )
+        .triple(
+            Term::var("header"),
+            Term::prefixed("http:fieldValue"),
+            Term::var("headerValue"),
+        )
+        .optional(vec![
+            Pattern::triple(
+                Term::var("device"),
+                Term::prefixed("td:hasActionAffordance"),
+                Term::var("command"),
+            ),
+            Pattern::Values {
+                vars: vec!["command".to_string()],
+                rows: known_commands,
+            },
+        ]);
+
+    // a malformed row would drop a device or its headers, so the cached devices are kept instead
+    let rows: Vec<DeviceHeaderRow> = send_query(&query).await?.into_complete()?;
+    log(format!("Query result: {:?}", rows));
+
+    let mut devices = WotDevices::new();
+    for row in rows {
+        let device = devices.entry(row.device).or_default();
+        device.headers.insert(row.header_name, row.header_value);
+        if let Some(capability) = row
+            .command
+            .as_deref()
+            .and_then(Capability::from_command_iri)
+        {
+            device.capabilities.insert(capability);
+        }
+    }
+
+    Ok(devices)
+}
+
+/// Fills the name, room, category and manufacturer of the devices, when the RDF graph has them.
+///
+/// They're fetched separately from the headers, so that the optional values don't multiply the rows of the other query.
+async fn fetch_devices_metadata(
+    environment_uuid: Uuid,
+    devices: &mut WotDevices,
+) -> Result<(), GenericError> {
+    let query = SelectQuery::new([
+        "device",
+        "name",
+        "room",
+        "roomName",
+        "category",
+        "manufacturer",
+    ])
+    .triple(
+        Term::from(environment_uuid),
+        Term::prefixed("bot:hasElement"),
+        Term::var("device"),
+    )
+    .triple(
+        Term::var("device"),
+        Term::prefixed("rdf:type"),
+        Term::prefixed("saref:Device"),
+    )
+    .optional(vec![Pattern::triple(
+        Term::var("device"),
+        Term::prefixed("rdfs:label"),
+        Term::var("name"),
+    )])
+    .optional(vec![
+        Pattern::triple(
+            Term::var("room"),
+            Term::prefixed("bot:containsElement"),
+            Term::var("device"),
+        ),
+        Pattern::triple(
+            Term::var("room"),
+            Term::prefixed("rdf:type"),
+            Term::prefixed("bot:Space"),
+        ),
+        Pattern::Optional(vec![Pattern::triple(
+            Term::var("room"),
+            Term::prefixed("rdfs:label"),
+            Term::var("roomName"),
+        )]),
+    ])
+    .optional(vec![
+        Pattern::triple(
+            Term::var("device"),
+            Term::prefixed("rdf:type"),
+            Term::var("category"),
+        ),
+        Pattern::Filter(Expression::not_equal(
+            Term::var("category"),
+            Term::prefixed("saref:Device"),
+        )),
+    ])
+    .optional(vec![Pattern::triple(
+        Term::var("device"),
+        Term::prefixed("saref:hasManufacturer"),
+        Term::var("manufacturer"),
+    )]);
+
+    let QueryRows { rows, errors } = send_query::<DeviceMetadataRow>(&query).await?;
+    log(format!("Query result: {:?}", rows));
+    for (i, e) in errors {
+        log(format!("Skipping malformed metadata result {i}: {e}"));
+    }
+
+    // a device with more than one value for a field gets the first one returned
+    for row in rows {
+        let Some(device) = devices.get_mut(&row.device) else {
+            continue;
+        };
+
+        device.name = device.name.take().or(row.name);
+        device.room = device.room.take().or(row.room.map(|iri| Room {
+            iri,
+            name: row.room_name,
+        }));
+        device.category = device.category.take().or(row.category);
+        device.manufacturer = device.manufacturer.take().or(row.manufacturer);
+    }
+
+    Ok(())
+}
+
+/// Records that the environment has been used, so that the timer keeps its devices fresh.
+///
+/// Query calls can't persist it, so it's called by the update calls on the environment.
+pub fn mark_environment_used(environment_uid: &EnvironmentUid) {
+    ENVIRONMENTS_LAST_USED.with(|last_used| {
+        last_used
+            .borrow_mut()
+            .insert(environment_uid.clone(), time())
+    });
+    arm_devices_refresh_timer();
+}
+
+/// Arms a one-shot timer for the next time an environment used recently goes stale,
+/// unless a timer armed before fires earlier. No timer is armed when no environment is used.
+///
+/// Must be called every time an environment is used or refreshed.
+/// The environments are forgotten on upgrades, so there's nothing to arm on post upgrade.
+fn arm_devices_refresh_timer() {
+    let now = time();
+    let next_timestamp = ENVIRONMENTS_LAST_USED.with(|last_used| {
+        let last_used = last_used.borrow();
+        let refreshing = REFRESHING_ENVIRONMENTS.with(|refreshing| refreshing.borrow().clone());
+        STATE.with(|state| {
+            let state = state.borrow();
+            last_used
+                .iter()
+                // the timer is armed again when their refresh ends
+                .filter(|(environment_uid, _)| !refreshing.contains(*environment_uid))
+                .filter_map(|(environment_uid, used_at)| {
+                    let environment = state.environment_devices.get(environment_uid)?;
+                    let refresh_timestamp = environment
+                        .fetched_at
+                        .saturating_add(DEVICES_TTL)
+                        .max(now.saturating_add(DEVICES_REFRESH_RETRY_DELAY));
+                    // an environment idle by then is not refreshed anymore
+                    (refresh_timestamp < used_at.saturating_add(ENVIRONMENT_IDLE_TIMEOUT))
+                        .then_some(refresh_timestamp)
+                })
+                .min()
+        })
+    });
+    let Some(next_timestamp) = next_timestamp else {
+        return;
+    };
+
+    DEVICES_REFRESH_TIMER.with(|t| {
+        let mut timer = t.borrow_mut();
+
+        // an earlier timer arms the next one when it fires, so each use doesn't postpone the refreshes
+        if let Some((_, armed_timestamp)) = *timer {
+            if armed_timestamp <= next_timestamp {
+                return;
+            }
+        }
+
+        if let Some((timer_id, _)) = timer.take() {
+            clear_timer(timer_id);
+        }
+
+        let delay = Duration::from_nanos(next_timestamp.saturating_sub(now));
+        *timer = Some((
+            set_timer(delay, devices_refresh_timer_callback),
+            next_timestamp,
+        ));
+    });
+}
+
+fn devices_refresh_timer_callback() {
+    // the timer has fired, it can't be cleared anymore
+    DEVICES_REFRESH_TIMER.with(|t| t.borrow_mut().take());
+
+    refresh_stale_environments();
+
+    arm_devices_refresh_timer();
+}
+
+fn refresh_stale_environments() {
+    let now = time();
+    let used_environments: Vec<EnvironmentUid> = ENVIRONMENTS_LAST_USED.with(|last_used| {
+        let mut last_used = last_used.borrow_mut();
+        // the idle environments are forgotten, they're refreshed on demand when used again
+        last_used.retain(|_, used_at| now.saturating_sub(*used_at) < ENVIRONMENT_IDLE_TIMEOUT);
+        last_used.keys().cloned().collect()
+    });
+    let stale_environments: Vec<EnvironmentUid> = STATE.with(|state| {
+        let state = state.borrow();
+        used_environments
+            .into_iter()
+            .filter(|environment_uid| {
+                state
+                    .environment_devices
+                    .get(environment_uid)
+                    .is_some_and(|environment| is_stale(&environment, now))
+            })
+            .collect()
+    });
+
+    for environment_uid in stale_environments {
+        let is_refreshing = REFRESHING_ENVIRONMENTS
+            .with(|refreshing| !refreshing.borrow_mut().insert(environment_uid.clone()));
+        if is_refreshing {
+            continue;
+        }
+
+        ic_cdk::spawn(async move {
+            // the environments are stored with their canonical uid, so this can't fail
+            if let Ok(environment_uuid) = Uuid::parse_str(&environment_uid) {
+                if let Err(e) = refresh_environment_devices(environment_uuid).await {
+                    log(format!(
+                        "Failed to refresh devices of environment {environment_uid}: {e}"
+                    ));
+                }
+            }
+
+            REFRESHING_ENVIRONMENTS
+                .with(|refreshing| refreshing.borrow_mut().remove(&environment_uid));
+            arm_devices_refresh_timer();
+        });
+    }
+}
@@ -1,51 +1,118 @@
+use acl::{AccessControl, EnvironmentAcl, Role};
 use candid::{CandidType, Deserialize, Principal};
 use commands::{
-    commands_interval_callback, CommandHttpArguments, CommandMetadata, DeviceCommand,
-    DeviceCommands,
+    arm_commands_timer, CommandId, CommandSchedule, CommandsQuery, CommandsSettings, CommandsView,
+    DeviceCommand, DeviceCommandView, DeviceCommands, HistoryPage, HistoryQuery, HistoryRetention,
+    RetryPolicy, ScheduleCommandReceipt,
+};
+use discovery::{
+    get_cached_devices, get_cached_environment, mark_environment_used, refresh_environment_devices,
+    CachedDevices,
 };
 use ic_cdk::{
     api::{
-        management_canister::{
-            http_request::{HttpHeader, HttpMethod},
-            provisional::CanisterId,
-        },
-        stable::{StableReader, StableWriter},
+        is_controller,
+        management_canister::{http_request::HttpHeader, provisional::CanisterId},
+        stable::StableReader,
+        time,
     },
-    caller, init, post_upgrade, pre_upgrade, print, query, update,
+    caller, init, post_upgrade, pre_upgrade, query, update,
 };
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use light::LightCommand;
+use memory::{
+    get_memory, is_legacy_stable_memory, Memory, ENVIRONMENT_DEVICES_MEMORY_ID,
+    HEAP_STATE_MEMORY_ID, THING_DESCRIPTIONS_MEMORY_ID,
+};
+use migrations::{migrate, LegacyState, StateEnvelope};
 use omnia_core_sdk::{
     access_key::{request_access_key, AccessKeyUID},
     http::get_request_headers,
     InitParams,
 };
-use rdf::{send_query, GenericError};
+use rdf::GenericError;
 use serde::Serialize;
-use std::{cell::RefCell, ops::Deref, str::FromStr, time::Duration};
-use utils::get_hue_from_color;
+use std::{cell::RefCell, collections::BTreeSet, str::FromStr};
+use topology::Zone;
+use utils::log;
 use uuid::Uuid;
-use wot::{DeviceUrl, WotDevices};
+use wot::{
+    fetch_thing_description, Capability, Device, DeviceUrl, EnvironmentDevices, EnvironmentUid,
+    ThingDescription,
+};
 
+mod acl;
 mod commands;
+mod cron;
+mod discovery;
+mod light;
+mod memory;
+mod migrations;
 mod outcalls;
 mod rdf;
+mod topology;
 mod utils;
 mod wot;
 
-#[derive(Default, CandidType, Serialize, Deserialize)]
+/// The state lives in stable memory, so that upgrades don't need to serialize it.
 struct State {
-    pub wot_devices: WotDevices,
+    /// The devices of each environment, stored when they're fetched.
+    pub environment_devices: StableBTreeMap<EnvironmentUid, EnvironmentDevices, Memory>,
+    /// Thing Descriptions of the devices, fetched the first time a command is sent to them after their environment is refreshed.
+    pub thing_descriptions: StableBTreeMap<DeviceUrl, ThingDescription, Memory>,
     pub device_commands: DeviceCommands,
+    pub access_control: AccessControl,
     pub last_valid_access_key: Option<AccessKeyUID>,
+    /// Where the fields kept on the heap are saved on upgrades, see [migrations].
+    heap_state: StableCell<StateEnvelope, Memory>,
 }
 
-thread_local! {
-    /* stable */ static STATE: RefCell<State>  = RefCell::new(State::default());
+/// The fields of the state kept on the heap, small enough to be saved to stable memory on upgrades.
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct HeapState {
+    last_valid_access_key: Option<AccessKeyUID>,
+    commands_settings: CommandsSettings,
 }
 
-fn start_commands_interval() {
-    ic_cdk_timers::set_timer_interval(Duration::new(1, 0), commands_interval_callback);
+impl State {
+    /// Opens the state on the stable structures.
+    /// The heap fields are loaded after the migrations in `post_upgrade`, see [State::load_heap_state].
+    fn init() -> Self {
+        let heap_state = StableCell::init(
+            get_memory(HEAP_STATE_MEMORY_ID),
+            StateEnvelope::new(&HeapState::default()),
+        )
+        .expect("failed to init heap state");
+
+        Self {
+            environment_devices: StableBTreeMap::init(get_memory(ENVIRONMENT_DEVICES_MEMORY_ID)),
+            thing_descriptions: StableBTreeMap::init(get_memory(THING_DESCRIPTIONS_MEMORY_ID)),
+            device_commands: DeviceCommands::init(CommandsSettings::default()),
+            access_control: AccessControl::init(),
+            last_valid_access_key: None,
+            heap_state,
+        }
+    }
+
+    fn load_heap_state(&mut self, heap_state: HeapState) {
+        self.last_valid_access_key = heap_state.last_valid_access_key;
+        self.device_commands
+            .set_settings(heap_state.commands_settings);
+    }
+
+    fn save_heap_state(&mut self) {
+        let heap_state = HeapState {
+            last_valid_access_key: self.last_valid_access_key.clone(),
+            commands_settings: self.device_commands.get_settings(),
+        };
+        self.heap_state
+            .set(StateEnvelope::new(&heap_state))
+            .expect("failed to save heap state");
+    }
+}
 
-    print("Started commands interval: 1 second");
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::init());
 }
 
 #[init]
@@ -54,7 +121,7 @@ fn init(
     omnia_backend_canister_id: Option<String>,
     ledger_canister_id: Option<String>,
 ) {
-    print("Init canister...");
+    log("Init canister...");
 
     // initialize the omnia sdk
     omnia_core_sdk::init_client(InitParams {
@@ -72,16 +139,11 @@ fn init(
             None => None,
         },
     });
-
-    start_commands_interval();
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    STATE.with(|state| {
-        ciborium::ser::into_writer(state.borrow().deref(), StableWriter::default())
-            .expect("failed to encode state")
-    })
+    STATE.with(|state| state.borrow_mut().save_heap_state())
 }
 
 #[post_upgrade]
@@ -90,11 +152,24 @@ fn post_upgrade(
     omnia_backend_canister_id: Option<String>,
     ledger_canister_id: Option<String>,
 ) {
-    print("Post upgrade canister...");
+    log("Post upgrade canister...");
+
+    // the previous versions dumped the whole state to stable memory,
+    // which must be read before the state is opened on the stable structures
+    let legacy_state: Option<LegacyState> = is_legacy_stable_memory().then(|| {
+        ciborium::de::from_reader(StableReader::default()).expect("failed to decode legacy state")
+    });
 
     STATE.with(|cell| {
-        *cell.borrow_mut() =
-            ciborium::de::from_reader(StableReader::default()).expect("failed to decode state");
+        let mut state = cell.borrow_mut();
+        let heap_state = migrate(&mut state, legacy_state);
+        state.load_heap_state(heap_state);
+        // save the state with the current version right away, in case the next upgrade fails before saving it
+        state.save_heap_state();
+        // the executions in progress before the upgrade are not going to complete
+        state
+            .device_commands
+            .recover_running_commands(u64::MAX, time());
     });
 
     // re-initialize the omnia sdk
@@ -114,41 +189,181 @@ fn post_upgrade(
         },
     });
 
-    start_commands_interval();
+    // the timers don't survive upgrades
+    arm_commands_timer();
 }
 
+/// Returns the environment uid in its canonical form, so that it can be used as a key.
+fn parse_environment_uid(environment_uid: &str) -> Result<Uuid, GenericError> {
+    Uuid::parse_str(environment_uid).map_err(|op| op.to_string())
+}
+
+/// Returns an error if the user doesn't have at least the given role on the environment.
+fn check_environment_role(
+    environment_uid: &EnvironmentUid,
+    user: &Principal,
+    role: Role,
+) -> Result<(), GenericError> {
+    let user_role = STATE.with(|state| {
+        state
+            .borrow()
+            .access_control
+            .get_environment_role(user, environment_uid)
+    });
+
+    if user_role < Some(role) {
+        return Err(format!(
+            "{role:?} role required on environment {environment_uid}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns an error if the user doesn't have at least the given role on the device of the environment.
+fn check_device_role(
+    environment_uid: &EnvironmentUid,
+    device_url: &DeviceUrl,
+    user: &Principal,
+    role: Role,
+) -> Result<(), GenericError> {
+    let user_role = STATE.with(|state| {
+        state
+            .borrow()
+            .access_control
+            .get_role(user, environment_uid, Some(device_url))
+    });
+
+    if user_role < Some(role) {
+        return Err(format!("{role:?} role required on device {device_url}"));
+    }
+
+    Ok(())
+}
+
+/// Make a principal the owner of an environment.
+///
+/// Once claimed, only the principals the owner grants a role to can view and operate its devices.
+///
+/// Only controllers of the canister can call this method, since the canister can't verify
+/// who owns an environment in the Omnia backend.
 #[update]
-async fn get_devices_in_environment(environment_uid: String) -> Result<WotDevices, GenericError> {
-    let environment_urn = Uuid::parse_str(&environment_uid)
-        .map_err(|op| op.to_string())?
-        .urn();
-
-    // with this query, we get all the devices in the environment that have the toggle capability
-    let query = format!(
-        r#"
-        SELECT ?device ?headerName ?headerValue WHERE {{
-            {environment_urn} bot:hasElement ?device .
-            ?device rdf:type saref:Device .
-            ?device omnia:requiresHeader ?header .
-            ?header http:fieldName ?headerName ;
-                    http:fieldValue ?headerValue .
-        }}"#
-    );
-    print(format!("Query: {}", query));
+fn claim_environment(environment_uid: String, owner: Principal) -> Result<(), GenericError> {
+    if !is_controller(&caller()) {
+        return Err("Only controllers can claim environments".to_string());
+    }
+
+    if owner == Principal::anonymous() {
+        return Err("The owner must be authenticated".to_string());
+    }
+
+    let environment_uid = parse_environment_uid(&environment_uid)?.to_string();
+
+    STATE.with(|state| {
+        state
+            .borrow_mut()
+            .access_control
+            .claim_environment(environment_uid, owner)
+    })
+}
+
+#[derive(CandidType, Deserialize)]
+struct SetRoleInput {
+    environment_uid: String,
+    /// The device to set the role on, the whole environment if not provided.
+    device_url: Option<DeviceUrl>,
+    principal: Principal,
+}
+
+/// Set the role of a principal on an environment or on one of its devices.
+///
+/// Only admins of the environment, or of the device, can grant roles.
+fn set_role(input: SetRoleInput, role: Option<Role>) -> Result<(), GenericError> {
+    let user = caller();
+    let environment_uid = parse_environment_uid(&input.environment_uid)?.to_string();
+
+    match &input.device_url {
+        Some(device_url) => check_device_role(&environment_uid, device_url, &user, Role::Admin)?,
+        None => check_environment_role(&environment_uid, &user, Role::Admin)?,
+    }
+
+    STATE.with(|state| {
+        state.borrow_mut().access_control.set_role(
+            &environment_uid,
+            input.device_url,
+            input.principal,
+            role,
+        )
+    })
+}
+
+/// Grant a role to a principal on an environment or on one of its devices.
+///
+/// Only admins of the environment, or of the device, can grant roles.
+#[update]
+fn grant_role(input: SetRoleInput, role: Role) -> Result<(), GenericError> {
+    set_role(input, Some(role))
+}
+
+/// Revoke the role of a principal on an environment or on one of its devices.
+///
+/// Only admins of the environment, or of the device, can revoke roles.
+#[update]
+fn revoke_role(input: SetRoleInput) -> Result<(), GenericError> {
+    set_role(input, None)
+}
+
+/// Get the owner and the roles granted in an environment.
+///
+/// Only admins of the environment can call this method.
+#[query]
+fn get_environment_acl(environment_uid: String) -> Result<EnvironmentAcl, GenericError> {
+    let environment_uid = parse_environment_uid(&environment_uid)?.to_string();
+    check_environment_role(&environment_uid, &caller(), Role::Admin)?;
+
+    STATE
+        .with(|state| {
+            state
+                .borrow()
+                .access_control
+                .get_environment_acl(&environment_uid)
+        })
+        .ok_or_else(|| format!("Environment {environment_uid} is not claimed"))
+}
+
+/// Get the devices of an environment, as they were cached the last time they have been fetched.
+///
+/// If capabilities are given, only the devices that have all of them are returned.
+/// The devices that only advertise a `saref:ToggleCommand` don't have the `Power` capability,
+/// since toggling them can't tell whether they end up on or off.
+///
+/// Returns an error if they have never been fetched, in which case [refresh_environment] must be called.
+/// The devices are refreshed in the background, but they can be stale if the refresh failed.
+#[query]
+fn get_devices_in_environment(
+    environment_uid: String,
+    capabilities: Option<BTreeSet<Capability>>,
+) -> Result<CachedDevices, GenericError> {
+    let environment_uid = parse_environment_uid(&environment_uid)?.to_string();
+    check_environment_role(&environment_uid, &caller(), Role::Viewer)?;
+
+    get_cached_devices(&environment_uid, &capabilities.unwrap_or_default())
+}
 
-    let res = send_query(query).await?;
-    print(format!("Query result: {:?}", res));
+/// Fetch the devices of an environment from the Omnia backend, updating the cached ones.
+#[update]
+async fn refresh_environment(environment_uid: String) -> Result<CachedDevices, GenericError> {
+    let environment_uuid = parse_environment_uid(&environment_uid)?;
+    let environment_uid = environment_uuid.to_string();
+    check_environment_role(&environment_uid, &caller(), Role::Viewer)?;
+    mark_environment_used(&environment_uid);
 
-    // save the devices in the shared state, so that we can use them in the other methods
-    Ok(STATE.with(|state| {
-        state.borrow_mut().wot_devices = res;
-        state.borrow().wot_devices.clone()
-    }))
+    refresh_environment_devices(environment_uuid).await
 }
 
 // used just for development purposes
 // use std::collections::BTreeMap;
-// use wot::DeviceHeaders;
+// use wot::Device;
 // #[update]
 // async fn get_devices_in_environment(environment_uid: String) -> Result<WotDevices, GenericError> {
 //     let environment_urn = Uuid::parse_str(&environment_uid)
@@ -166,14 +381,14 @@ async fn get_devices_in_environment(environment_uid: String) -> Result<WotDevice
 //                     http:fieldValue ?headerValue .
 //         }}"#
 //     );
-//     print(format!("Query: {}", query));
+//     log(format!("Query: {}", query));
 
 //     // save the devices in the shared state, so that we can use them in the other methods
 //     Ok(STATE.with(|state| {
 //         let mut wot_devices = WotDevices::new();
 //         wot_devices.insert(
 //             String::from("https://lighting-app.free.beeceptor.com/todos"),
-//             DeviceHeaders {
+//             Device {
 //                 headers: BTreeMap::from([(
 //                     String::from("Accept"),
 //                     String::from("application/json"),
@@ -182,7 +397,7 @@ async fn get_devices_in_environment(environment_uid: String) -> Result<WotDevice
 //         );
 //         wot_devices.insert(
 //             String::from("https://lighting-app.free.beeceptor.com/todos?bla=ble"),
-//             DeviceHeaders {
+//             Device {
 //                 headers: BTreeMap::from([(
 //                     String::from("Accept"),
 //                     String::from("application/json"),
@@ -194,120 +409,460 @@ async fn get_devices_in_environment(environment_uid: String) -> Result<WotDevice
 //     }))
 // }
 
-#[derive(CandidType, Serialize, Deserialize)]
-struct ScheduleCommandInput {
-    device_url: DeviceUrl,
-    light_color: String,
-}
-
-/// Schedule a command to be sent to a device.
-#[update]
-async fn schedule_command(input: ScheduleCommandInput) -> Result<(), GenericError> {
-    let user = caller();
-
-    if user == Principal::anonymous() {
-        return Err("User not authenticated".to_string());
-    }
-
-    let devices = STATE.with(|state| state.borrow().wot_devices.clone());
-    // get the device requested
-    let device = devices
-        .get(&input.device_url)
-        .ok_or_else(|| "Device not found".to_string())?;
-
+/// Returns the last valid access key, requesting a new one if there's none.
+async fn get_access_key() -> Result<AccessKeyUID, GenericError> {
     // get the current access key
     let access_key = STATE.with(|state| state.borrow().last_valid_access_key.clone());
 
     // if access key is not there, we need to get a new one
-    let access_key = match access_key {
-        Some(access_key) => access_key,
+    match access_key {
+        Some(access_key) => Ok(access_key),
         None => {
             let access_key = request_access_key().await?;
             // store the new access key in the shared state, so that we can use it in the other calls
             STATE.with(|state| {
                 state.borrow_mut().last_valid_access_key = Some(access_key.clone());
             });
-            access_key
+            Ok(access_key)
         }
-    };
+    }
+}
+
+/// Returns the device of the environment, as fetched the last time.
+fn get_device(
+    environment_uid: &EnvironmentUid,
+    device_url: &DeviceUrl,
+) -> Result<Device, GenericError> {
+    get_cached_environment(environment_uid)?
+        .devices
+        .remove(device_url)
+        .ok_or_else(|| "Device not found".to_string())
+}
+
+/// Returns the headers needed to send a request to the device, including the access key ones.
+async fn get_device_request_headers(
+    environment_uid: &EnvironmentUid,
+    device_url: &DeviceUrl,
+) -> Result<Vec<HttpHeader>, GenericError> {
+    // get the device requested
+    let device = get_device(environment_uid, device_url)?;
+
+    let access_key = get_access_key().await?;
 
     // prepare the headers for the request
-    let headers = get_request_headers(
+    get_request_headers(
         access_key,
         Some(
             device
-                .clone()
                 .headers
                 .into_iter()
                 .map(|(k, v)| HttpHeader { name: k, value: v })
                 .collect::<Vec<HttpHeader>>(),
         ),
     )
-    .await?;
-
-    // here we should parse the device Thing Description to get the correct endpoint
-    // for now, we assume we already know it since we fetched the device with that capability
-    let url = format!("{}/actions/768", input.device_url);
-
-    // same for the body, we should parse the TD to get the correct body structure and format the request accordingly
-    let body = format!(
-        r#"{{
-        "command": {{
-            "id": 6,
-            "payload": {{
-                "0": {},
-                "1": 254,
-                "2": 0,
-                "3": 0,
-                "4": 0
-            }}
-        }}
-    }}"#,
-        get_hue_from_color(&input.light_color)
-    );
+    .await
+}
 
-    // same for the method
-    let method = HttpMethod::POST;
+/// Returns the Thing Description of the device, fetching it from the device if it's not cached yet.
+///
+/// The cached one is dropped every time the devices of its environment are refreshed.
+async fn get_thing_description(
+    device_url: &DeviceUrl,
+    headers: Vec<HttpHeader>,
+) -> Result<ThingDescription, GenericError> {
+    let cached = STATE.with(|state| state.borrow().thing_descriptions.get(device_url));
+
+    match cached {
+        Some(td) => Ok(td),
+        None => {
+            let td = fetch_thing_description(device_url, headers).await?;
+            STATE.with(|state| {
+                state
+                    .borrow_mut()
+                    .thing_descriptions
+                    .insert(device_url.clone(), td.clone());
+            });
+            Ok(td)
+        }
+    }
+}
 
-    let device_command = DeviceCommand::new(
+#[derive(CandidType, Serialize, Deserialize)]
+struct ScheduleCommandInput {
+    /// The environment the device is in, its devices must have been fetched before.
+    environment_uid: String,
+    device_url: DeviceUrl,
+    command: LightCommand,
+    /// When to execute the command, as soon as possible if not provided.
+    schedule: Option<CommandSchedule>,
+    /// How to retry the command if it fails, the default policy is used if not provided.
+    retry_policy: Option<RetryPolicy>,
+}
+
+/// Schedule a command to be sent to a device.
+///
+/// Returns a receipt with the unique id of the command, which can be used to track it with [get_command].
+#[update]
+async fn schedule_command(
+    input: ScheduleCommandInput,
+) -> Result<ScheduleCommandReceipt, GenericError> {
+    let user = caller();
+
+    if user == Principal::anonymous() {
+        return Err("User not authenticated".to_string());
+    }
+
+    let environment_uid = parse_environment_uid(&input.environment_uid)?.to_string();
+
+    let (schedule, retry_policy) =
+        validate_command_input(&input.command, input.schedule, input.retry_policy)?;
+
+    schedule_device_command(
+        user,
+        &environment_uid,
         input.device_url,
-        CommandHttpArguments {
-            url,
-            method,
-            headers,
-            body: Some(body.into()),
-        },
+        &input.command,
+        schedule,
+        retry_policy,
+    )
+    .await
+}
+
+/// Validates the command and its options, returning the schedule and the retry policy to use.
+fn validate_command_input(
+    command: &LightCommand,
+    schedule: Option<CommandSchedule>,
+    retry_policy: Option<RetryPolicy>,
+) -> Result<(CommandSchedule, RetryPolicy), GenericError> {
+    command.validate()?;
+    let schedule = schedule.unwrap_or(CommandSchedule::Now);
+    schedule.validate()?;
+    let retry_policy = retry_policy.unwrap_or_default();
+    retry_policy.validate()?;
+
+    Ok((schedule, retry_policy))
+}
+
+/// Schedules the command, already validated, on a device of the environment on behalf of the user.
+async fn schedule_device_command(
+    user: Principal,
+    environment_uid: &EnvironmentUid,
+    device_url: DeviceUrl,
+    command: &LightCommand,
+    schedule: CommandSchedule,
+    retry_policy: RetryPolicy,
+) -> Result<ScheduleCommandReceipt, GenericError> {
+    // the device must be in the environment, so that the role on the environment applies to it
+    get_device(environment_uid, &device_url)?;
+    let role = STATE.with(|state| {
+        state
+            .borrow()
+            .access_control
+            .get_role(&user, environment_uid, Some(&device_url))
+    });
+    if role < Some(Role::Operator) {
+        return Err(format!("Operator role required on device {device_url}"));
+    }
+    mark_environment_used(environment_uid);
+
+    let headers = get_device_request_headers(environment_uid, &device_url).await?;
+
+    let thing_description = get_thing_description(&device_url, headers.clone()).await?;
+
+    let requests = command.to_requests(&thing_description, &device_url, &headers)?;
+
+    let device_command = DeviceCommand::new(
+        environment_uid.clone(),
+        device_url,
+        requests,
         0, // initializing the timestamp to 0 because it's set in the schedule_command function
         user,
-        Some(CommandMetadata {
-            light_color: input.light_color,
-        }),
+        Some(command.metadata()),
+        retry_policy,
     );
 
+    let receipt = STATE.with(|state| {
+        state
+            .borrow_mut()
+            .device_commands
+            .schedule_command(device_command, schedule, time())
+    })?;
+
+    arm_commands_timer();
+
+    Ok(receipt)
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct ScheduleZoneCommandInput {
+    /// The environment the zone is in, its devices must have been fetched before.
+    environment_uid: String,
+    /// The IRI of the zone, as returned by [get_environment_zones].
+    zone_iri: String,
+    command: LightCommand,
+    /// When to execute the commands, as soon as possible if not provided.
+    schedule: Option<CommandSchedule>,
+    /// How to retry the commands if they fail, the default policy is used if not provided.
+    retry_policy: Option<RetryPolicy>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct ScheduleZoneCommandReceipt {
+    /// The commands scheduled, one for each device.
+    scheduled: Vec<(DeviceUrl, ScheduleCommandReceipt)>,
+    /// The devices of the zone the command has not been scheduled on, with the reason.
+    skipped: Vec<(DeviceUrl, GenericError)>,
+}
+
+/// Get the zones of the buildings of an environment, like its storeys and rooms, as fetched with its devices.
+#[query]
+fn get_environment_zones(environment_uid: String) -> Result<Vec<Zone>, GenericError> {
+    let environment_uid = parse_environment_uid(&environment_uid)?.to_string();
+    check_environment_role(&environment_uid, &caller(), Role::Viewer)?;
+
+    Ok(get_cached_environment(&environment_uid)?
+        .zones
+        .into_values()
+        .collect())
+}
+
+/// Schedule a command to be sent to all the devices of a zone, like a room or a whole storey,
+/// that have the capabilities needed by the command.
+///
+/// A command is scheduled for each device, the devices it can't be scheduled on are returned in the receipt.
+#[update]
+async fn schedule_zone_command(
+    input: ScheduleZoneCommandInput,
+) -> Result<ScheduleZoneCommandReceipt, GenericError> {
+    let user = caller();
+
+    if user == Principal::anonymous() {
+        return Err("User not authenticated".to_string());
+    }
+
+    let environment_uid = parse_environment_uid(&input.environment_uid)?.to_string();
+
+    let (schedule, retry_policy) =
+        validate_command_input(&input.command, input.schedule, input.retry_policy)?;
+
+    let environment = get_cached_environment(&environment_uid)?;
+    let zone = environment
+        .zones
+        .get(&input.zone_iri)
+        .ok_or_else(|| format!("Zone {} not found", input.zone_iri))?;
+
+    let required_capabilities = input.command.required_capabilities();
+    let mut receipt = ScheduleZoneCommandReceipt {
+        scheduled: vec![],
+        skipped: vec![],
+    };
+
+    for device_url in &zone.devices {
+        let is_capable = environment
+            .devices
+            .get(device_url)
+            .is_some_and(|device| device.capabilities.is_superset(&required_capabilities));
+        if !is_capable {
+            receipt.skipped.push((
+                device_url.clone(),
+                "The device doesn't support the command".to_string(),
+            ));
+            continue;
+        }
+
+        match schedule_device_command(
+            user,
+            &environment_uid,
+            device_url.clone(),
+            &input.command,
+            schedule.clone(),
+            retry_policy.clone(),
+        )
+        .await
+        {
+            Ok(device_receipt) => receipt.scheduled.push((device_url.clone(), device_receipt)),
+            Err(e) => receipt.skipped.push((device_url.clone(), e)),
+        }
+    }
+
+    Ok(receipt)
+}
+
+/// Returns an error if the user can't modify the command:
+/// operators can modify the commands they sent, admins all the commands of the device.
+fn check_command_access(command_id: CommandId, user: &Principal) -> Result<(), GenericError> {
+    let (command, user_role) = STATE.with(|state| {
+        let state = state.borrow();
+        let command = state
+            .device_commands
+            .get_command(command_id)
+            .ok_or_else(|| "Command not found".to_string())?;
+        let user_role = get_command_role(&state, &command, user);
+
+        Ok::<_, GenericError>((command, user_role))
+    })?;
+
+    let role = if command.sender == *user {
+        Role::Operator
+    } else {
+        Role::Admin
+    };
+
+    if user_role < Some(role) {
+        return Err(format!(
+            "{role:?} role required on device {}",
+            command.device_url
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the role of the user on the device of the command, in the environment the command was sent to.
+///
+/// The commands sent before they recorded their environment fall back to the current environment of the device.
+fn get_command_role(state: &State, command: &DeviceCommand, user: &Principal) -> Option<Role> {
+    match &command.environment_uid {
+        Some(environment_uid) => {
+            state
+                .access_control
+                .get_role(user, environment_uid, Some(&command.device_url))
+        }
+        None => state
+            .access_control
+            .get_device_role(user, &command.device_url),
+    }
+}
+
+/// Returns true if the user can view the command, because they sent it or can view the device.
+fn can_view_command(state: &State, command: &DeviceCommand, user: &Principal) -> bool {
+    command.sender == *user || get_command_role(state, command, user).is_some()
+}
+
+/// Cancel a scheduled command.
+///
+/// Operators can cancel the commands they sent, admins of the device all of them.
+#[update]
+fn cancel_command(command_id: CommandId) -> Result<(), GenericError> {
+    check_command_access(command_id, &caller())?;
+
     STATE.with(|state| {
         state
             .borrow_mut()
             .device_commands
-            .schedule_command(device_command)
+            .cancel_command(command_id, time())
+    })?;
+
+    arm_commands_timer();
+
+    Ok(())
+}
+
+/// Move a scheduled command to another timestamp (in nanoseconds).
+///
+/// Operators can reschedule the commands they sent, admins of the device all of them.
+#[update]
+fn reschedule_command(
+    command_id: CommandId,
+    timestamp: u64,
+) -> Result<ScheduleCommandReceipt, GenericError> {
+    check_command_access(command_id, &caller())?;
+
+    let receipt = STATE.with(|state| {
+        state
+            .borrow_mut()
+            .device_commands
+            .reschedule_command(command_id, timestamp, time())
+    })?;
+
+    arm_commands_timer();
+
+    Ok(receipt)
+}
+
+/// Get a single command by its id, to track its status.
+#[query]
+fn get_command(command_id: CommandId) -> Option<DeviceCommandView> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        state
+            .device_commands
+            .get_command(command_id)
+            .filter(|c| can_view_command(&state, c, &caller()))
+            .map(DeviceCommandView::from)
+    })
+}
+
+/// Set the minimum interval between two commands sent to a device (in nanoseconds).
+///
+/// Only controllers of the canister can call this method.
+#[update]
+fn set_device_commands_interval(device_url: DeviceUrl, interval: u64) -> Result<(), GenericError> {
+    if !is_controller(&caller()) {
+        return Err("Only controllers can set the commands interval".to_string());
+    }
+
+    STATE.with(|state| {
+        state
+            .borrow_mut()
+            .device_commands
+            .set_device_interval(device_url, interval)
+    });
+
+    Ok(())
+}
+
+/// Set how many finished commands are kept, and for how long.
+///
+/// At most 10,000 finished commands can be kept.
+#[update]
+fn set_history_retention(retention: HistoryRetention) -> Result<(), GenericError> {
+    if !is_controller(&caller()) {
+        return Err("Only controllers can set the history retention".to_string());
+    }
+
+    retention.validate()?;
+
+    STATE.with(|state| {
+        state
+            .borrow_mut()
+            .device_commands
+            .set_history_retention(retention, time())
     });
 
+    // the finished commands expire at a different time
+    arm_commands_timer();
+
     Ok(())
 }
 
+/// Get a page of the finished commands, from the most recently finished to the least recently finished.
+///
+/// Pass the `next_cursor` of a page in the query to get the next one.
 #[query]
-fn get_commands() -> DeviceCommands {
+fn get_commands_history(query: HistoryQuery) -> HistoryPage {
+    let user = caller();
+
     STATE.with(|state| {
-        let state = &state.borrow().device_commands;
-
-        DeviceCommands {
-            finished_commands: state
-                .finished_commands
-                .iter()
-                .rev()
-                .take(10)
-                .map(|(t, c)| (t.clone(), c.clone()))
-                .collect(),
-            ..state.clone()
-        }
+        let state = state.borrow();
+        state
+            .device_commands
+            .get_history(&query, |c| can_view_command(&state, c, &user))
+    })
+}
+
+/// Get the commands the caller can view: a page of the scheduled ones, the running ones and the last finished ones.
+///
+/// Pass the `next_cursor` of a view in the query to get the next page of the scheduled commands,
+/// and use `get_commands_history` to get the other finished commands.
+#[query]
+fn get_commands(query: CommandsQuery) -> CommandsView {
+    let user = caller();
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        state
+            .device_commands
+            .get_commands_view(&query, |c| can_view_command(&state, c, &user))
     })
 }
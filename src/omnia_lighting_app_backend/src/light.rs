@@ -0,0 +1,670 @@
+use std::{collections::BTreeSet, str::FromStr};
+
+use candid::CandidType;
+use ic_cdk::api::management_canister::http_request::HttpHeader;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    commands::{CommandHttpArguments, CommandMetadata},
+    rdf::GenericError,
+    utils::LightColor,
+    wot::{ActionParams, Capability, DeviceUrl, ThingDescription},
+};
+
+/// The maximum value of the level in the Zigbee Level Control cluster.
+const ZIGBEE_MAX_LEVEL: f64 = 254.0;
+
+/// Describes how to find a light action in a Thing Description:
+/// first by semantic type, then by the name of the Zigbee cluster that implements it.
+struct LightAction {
+    /// The IRIs of the semantic types, which the TD can write in full or prefixed.
+    semantic_types: &'static [&'static str],
+    zigbee_action: &'static str,
+}
+
+const POWER_ACTION: LightAction = LightAction {
+    semantic_types: Capability::Power.command_iris(),
+    // Zigbee On/Off cluster
+    zigbee_action: "6",
+};
+
+const BRIGHTNESS_ACTION: LightAction = LightAction {
+    semantic_types: Capability::SetLevel.command_iris(),
+    // Zigbee Level Control cluster
+    zigbee_action: "8",
+};
+
+const COLOR_ACTION: LightAction = LightAction {
+    semantic_types: Capability::SetColor.command_iris(),
+    // Zigbee Color Control cluster
+    zigbee_action: "768",
+};
+
+const COLOR_TEMPERATURE_ACTION: LightAction = LightAction {
+    semantic_types: &["http://rdf.omnia-iot.com#SetColorTemperatureCommand"],
+    // Zigbee Color Control cluster
+    zigbee_action: "768",
+};
+
+impl LightAction {
+    /// Returns the name of the action in the TD, if the device supports it.
+    fn find(&self, td: &ThingDescription) -> Option<String> {
+        self.semantic_types
+            .iter()
+            .find_map(|t| td.find_action_by_type(t))
+            .cloned()
+            .or_else(|| {
+                td.actions
+                    .contains_key(self.zigbee_action)
+                    .then(|| self.zigbee_action.to_string())
+            })
+    }
+}
+
+/// A command for a light. All the fields are optional, but at least one must be set.
+#[derive(Clone, Debug, Default, CandidType, Serialize, Deserialize)]
+pub struct LightCommand {
+    /// Turns the light on or off.
+    pub power: Option<bool>,
+    /// The color to set, see [LightColor] for the accepted formats.
+    ///
+    /// Example: `#ff0000` for red.
+    pub light_color: Option<String>,
+    /// The hue to set, in degrees.
+    pub hue: Option<u16>,
+    /// The saturation to set along with the hue, in percent. Full saturation if not provided.
+    pub saturation: Option<u8>,
+    /// The brightness to set, in percent.
+    pub brightness: Option<u8>,
+    /// The color temperature to set, in Kelvin.
+    pub color_temperature: Option<u16>,
+    /// The transition time, in tenths of a second.
+    pub transition_time: Option<u16>,
+}
+
+impl LightCommand {
+    pub fn validate(&self) -> Result<(), GenericError> {
+        if self.power.is_none()
+            && self.light_color.is_none()
+            && self.hue.is_none()
+            && self.brightness.is_none()
+            && self.color_temperature.is_none()
+        {
+            return Err(String::from("The command doesn't set anything"));
+        }
+
+        if let Some(brightness) = self.brightness {
+            if brightness > 100 {
+                return Err(String::from("Brightness must be between 0 and 100"));
+            }
+        }
+
+        if let Some(hue) = self.hue {
+            if hue > 360 {
+                return Err(String::from("Hue must be between 0 and 360"));
+            }
+        }
+
+        if let Some(saturation) = self.saturation {
+            if saturation > 100 {
+                return Err(String::from("Saturation must be between 0 and 100"));
+            }
+            if self.hue.is_none() {
+                return Err(String::from(
+                    "Saturation can only be set along with the hue",
+                ));
+            }
+        }
+
+        let colors_count = [
+            self.light_color.is_some(),
+            self.hue.is_some(),
+            self.color_temperature.is_some(),
+        ]
+        .into_iter()
+        .filter(|is_set| *is_set)
+        .count();
+        if colors_count > 1 {
+            return Err(String::from(
+                "Only one of color, hue and color temperature can be set",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the capabilities a device needs to execute the command.
+    ///
+    /// The color temperature is not advertised as a capability, so only the Thing Description tells if it's supported.
+    pub fn required_capabilities(&self) -> BTreeSet<Capability> {
+        [
+            (self.power.is_some(), Capability::Power),
+            (self.brightness.is_some(), Capability::SetLevel),
+            (
+                self.light_color.is_some() || self.hue.is_some(),
+                Capability::SetColor,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(is_set, capability)| is_set.then_some(capability))
+        .collect()
+    }
+
+    /// Maps the command onto the device actions, returning the requests to send in order.
+    ///
+    /// The light is turned on before and turned off after the other actions,
+    /// so that the requested state is the one the light ends up in.
+    pub fn to_requests(
+        &self,
+        td: &ThingDescription,
+        device_url: &DeviceUrl,
+        headers: &[HttpHeader],
+    ) -> Result<Vec<CommandHttpArguments>, GenericError> {
+        self.validate()?;
+
+        let mut actions: Vec<(String, ActionParams)> = vec![];
+
+        let power_action = match self.power {
+            Some(on) => Some((
+                POWER_ACTION
+                    .find(td)
+                    .ok_or_else(|| String::from("The device can't be turned on or off"))?,
+                ActionParams::from([(String::from("on"), Value::from(on))]),
+            )),
+            None => None,
+        };
+
+        if let Some(power_action) = &power_action {
+            if self.power == Some(true) {
+                actions.push(power_action.clone());
+            }
+        }
+
+        if let Some((light_action, color)) = self.get_color()? {
+            // devices without a color temperature action can still approximate it with the color action
+            let action = light_action
+                .find(td)
+                .or_else(|| COLOR_ACTION.find(td))
+                .ok_or_else(|| String::from("The device doesn't support colors"))?;
+            actions.push((action, self.with_transition(color_params(color))));
+        }
+
+        if let Some(brightness) = self.brightness {
+            let action = BRIGHTNESS_ACTION
+                .find(td)
+                .ok_or_else(|| String::from("The device can't be dimmed"))?;
+            let level = (brightness as f64 / 100.0 * ZIGBEE_MAX_LEVEL).round() as u8;
+            actions.push((
+                action,
+                self.with_transition(ActionParams::from([
+                    (String::from("brightness"), Value::from(brightness)),
+                    (String::from("level"), Value::from(level)),
+                ])),
+            ));
+        }
+
+        if let Some(power_action) = power_action {
+            if self.power == Some(false) {
+                actions.push(power_action);
+            }
+        }
+
+        actions
+            .into_iter()
+            .map(|(name, params)| {
+                let action = td.resolve_action(&name, device_url)?;
+                let body = action.build_body(&params)?;
+
+                let mut headers = headers.to_vec();
+                headers.push(HttpHeader {
+                    name: String::from("Content-Type"),
+                    value: action.content_type,
+                });
+
+                Ok(CommandHttpArguments {
+                    url: action.href,
+                    method: action.method,
+                    headers,
+                    body: Some(body),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the color to set, with the action that sets it.
+    fn get_color(&self) -> Result<Option<(&'static LightAction, LightColor)>, GenericError> {
+        if let Some(light_color) = &self.light_color {
+            return Ok(Some((&COLOR_ACTION, LightColor::from_str(light_color)?)));
+        }
+        if let Some(hue) = self.hue {
+            let color = LightColor::Hsv {
+                h: (hue % 360) as f64,
+                s: self.saturation.unwrap_or(100) as f64 / 100.0,
+                v: 1.0,
+            };
+            return Ok(Some((&COLOR_ACTION, color)));
+        }
+        if let Some(kelvin) = self.color_temperature {
+            return Ok(Some((
+                &COLOR_TEMPERATURE_ACTION,
+                LightColor::from_str(&format!("{kelvin}K"))?,
+            )));
+        }
+
+        Ok(None)
+    }
+
+    fn with_transition(&self, mut params: ActionParams) -> ActionParams {
+        if let Some(transition_time) = self.transition_time {
+            params.insert(String::from("transitionTime"), Value::from(transition_time));
+        }
+        params
+    }
+
+    /// Returns what the command sets, including the hue and saturation sent for any color.
+    pub fn metadata(&self) -> CommandMetadata {
+        let (hue, saturation) = match self.get_color() {
+            Ok(Some((_, color))) => {
+                let (h, s, _) = color.to_hsv();
+                (
+                    Some(h.round() as u16 % 360),
+                    Some((s * 100.0).round() as u8),
+                )
+            }
+            _ => (None, None),
+        };
+
+        CommandMetadata {
+            light_color: self.light_color.clone(),
+            hue,
+            saturation,
+            power: self.power,
+            brightness: self.brightness,
+            color_temperature: self.color_temperature,
+            transition_time: self.transition_time,
+        }
+    }
+}
+
+/// Provides all the representations of the color and lets the action input schema pick the ones it needs.
+fn color_params(color: LightColor) -> ActionParams {
+    let (hue, saturation) = color.to_hue_saturation();
+    let (x, y) = color.to_cie_xy();
+
+    let mut params = ActionParams::from([
+        (String::from("hue"), Value::from(hue)),
+        (String::from("saturation"), Value::from(saturation)),
+        (String::from("x"), Value::from(x)),
+        (String::from("y"), Value::from(y)),
+    ]);
+    if let Some(mireds) = color.to_mireds() {
+        params.insert(String::from("colorTemperature"), Value::from(mireds));
+    }
+
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const DEVICE_URL: &str = "https://gw/things/lamp/";
+
+    /// A lamp with power, brightness and color actions, which advertises its action types
+    /// both prefixed and in full, or only with the Zigbee cluster name.
+    fn lamp(extra_actions: Value) -> ThingDescription {
+        let mut td = json!({
+            "title": "Lamp",
+            "actions": {
+                "power": {
+                    "@type": "saref:OnOffCommand",
+                    "input": { "properties": { "on": { "type": "boolean" } }, "required": ["on"] },
+                    "forms": [{ "href": "actions/power" }],
+                },
+                "dim": {
+                    "@type": ["https://saref.etsi.org/core/SetLevelCommand"],
+                    "input": { "properties": {
+                        "level": { "type": "integer", "minimum": 0, "maximum": 254 },
+                        "transitionTime": { "type": "integer" },
+                    } },
+                    "forms": [{ "href": "actions/dim" }],
+                },
+                "768": {
+                    "input": { "properties": {
+                        "hue": { "type": "integer" },
+                        "saturation": { "type": "integer" },
+                    } },
+                    "forms": [{ "href": "actions/color" }],
+                },
+            },
+        });
+        td["actions"]
+            .as_object_mut()
+            .unwrap()
+            .extend(extra_actions.as_object().unwrap().clone());
+        serde_json::from_value(td).unwrap()
+    }
+
+    fn requests(command: &LightCommand, td: &ThingDescription) -> Vec<(String, Value)> {
+        command
+            .to_requests(td, &DEVICE_URL.to_string(), &[])
+            .unwrap()
+            .into_iter()
+            .map(|request| {
+                (
+                    request.url.trim_start_matches(DEVICE_URL).to_string(),
+                    serde_json::from_slice(&request.body.unwrap()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn turns_the_light_on_before_the_other_actions() {
+        let command = LightCommand {
+            power: Some(true),
+            light_color: Some(String::from("red")),
+            brightness: Some(50),
+            transition_time: Some(10),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            requests(&command, &lamp(json!({}))),
+            vec![
+                (String::from("actions/power"), json!({ "on": true })),
+                (
+                    String::from("actions/color"),
+                    json!({ "hue": 0, "saturation": 254 })
+                ),
+                (
+                    String::from("actions/dim"),
+                    json!({ "level": 127, "transitionTime": 10 })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn turns_the_light_off_after_the_other_actions() {
+        let command = LightCommand {
+            power: Some(false),
+            brightness: Some(100),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            requests(&command, &lamp(json!({}))),
+            vec![
+                (String::from("actions/dim"), json!({ "level": 254 })),
+                (String::from("actions/power"), json!({ "on": false })),
+            ]
+        );
+    }
+
+    #[test]
+    fn sends_the_headers_along_with_the_content_type() {
+        let command = LightCommand {
+            power: Some(true),
+            ..Default::default()
+        };
+        let access_key = HttpHeader {
+            name: String::from("X-Access-Key"),
+            value: String::from("key"),
+        };
+
+        let requests = command
+            .to_requests(
+                &lamp(json!({})),
+                &DEVICE_URL.to_string(),
+                std::slice::from_ref(&access_key),
+            )
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].headers,
+            vec![
+                access_key,
+                HttpHeader {
+                    name: String::from("Content-Type"),
+                    value: String::from("application/json"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_color_action_for_color_temperatures() {
+        let command = LightCommand {
+            color_temperature: Some(2_700),
+            ..Default::default()
+        };
+
+        // approximated with the hue and saturation of rgb(255, 167, 87)
+        assert_eq!(
+            requests(&command, &lamp(json!({}))),
+            vec![(
+                String::from("actions/color"),
+                json!({ "hue": 20, "saturation": 167 })
+            )]
+        );
+
+        let td = lamp(json!({
+            "temperature": {
+                "@type": "http://rdf.omnia-iot.com#SetColorTemperatureCommand",
+                "input": { "properties": { "colorTemperature": { "type": "integer" } } },
+                "forms": [{ "href": "actions/temperature" }],
+            },
+        }));
+        assert_eq!(
+            requests(&command, &td),
+            vec![(
+                String::from("actions/temperature"),
+                json!({ "colorTemperature": 370 })
+            )]
+        );
+    }
+
+    #[test]
+    fn finds_the_actions_by_prefixed_or_full_semantic_type() {
+        let command = LightCommand {
+            power: Some(true),
+            ..Default::default()
+        };
+
+        for semantic_type in [
+            json!("omnia:SetPowerCommand"),
+            json!("http://rdf.omnia-iot.com#SetPowerCommand"),
+            json!(["saref:Command", "https://saref.etsi.org/core/OnOffCommand"]),
+        ] {
+            let td: ThingDescription = serde_json::from_value(json!({
+                "title": "Plug",
+                "actions": { "switch": {
+                    "@type": semantic_type,
+                    "forms": [{ "href": "actions/switch" }],
+                } },
+            }))
+            .unwrap();
+            assert_eq!(
+                requests(&command, &td),
+                vec![(String::from("actions/switch"), json!({ "on": true }))],
+                "{semantic_type}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_the_commands_the_device_doesnt_support() {
+        let td: ThingDescription =
+            serde_json::from_value(json!({ "title": "Bulb", "actions": {} })).unwrap();
+
+        for (command, expected) in [
+            (
+                LightCommand {
+                    power: Some(true),
+                    ..Default::default()
+                },
+                "The device can't be turned on or off",
+            ),
+            (
+                LightCommand {
+                    brightness: Some(50),
+                    ..Default::default()
+                },
+                "The device can't be dimmed",
+            ),
+            (
+                LightCommand {
+                    color_temperature: Some(2_700),
+                    ..Default::default()
+                },
+                "The device doesn't support colors",
+            ),
+        ] {
+            assert_eq!(
+                command
+                    .to_requests(&td, &DEVICE_URL.to_string(), &[])
+                    .unwrap_err(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        for (command, expected) in [
+            (
+                LightCommand {
+                    transition_time: Some(10),
+                    ..Default::default()
+                },
+                "The command doesn't set anything",
+            ),
+            (
+                LightCommand {
+                    brightness: Some(101),
+                    ..Default::default()
+                },
+                "Brightness must be between 0 and 100",
+            ),
+            (
+                LightCommand {
+                    hue: Some(361),
+                    ..Default::default()
+                },
+                "Hue must be between 0 and 360",
+            ),
+            (
+                LightCommand {
+                    hue: Some(120),
+                    saturation: Some(101),
+                    ..Default::default()
+                },
+                "Saturation must be between 0 and 100",
+            ),
+            (
+                LightCommand {
+                    power: Some(true),
+                    saturation: Some(50),
+                    ..Default::default()
+                },
+                "Saturation can only be set along with the hue",
+            ),
+            (
+                LightCommand {
+                    light_color: Some(String::from("red")),
+                    color_temperature: Some(2_700),
+                    ..Default::default()
+                },
+                "Only one of color, hue and color temperature can be set",
+            ),
+        ] {
+            assert_eq!(command.validate(), Err(expected.to_string()));
+            assert_eq!(
+                command
+                    .to_requests(&lamp(json!({})), &DEVICE_URL.to_string(), &[])
+                    .unwrap_err(),
+                expected
+            );
+        }
+
+        let command = LightCommand {
+            light_color: Some(String::from("blurple")),
+            ..Default::default()
+        };
+        assert_eq!(
+            command
+                .to_requests(&lamp(json!({})), &DEVICE_URL.to_string(), &[])
+                .unwrap_err(),
+            "Color not supported: blurple"
+        );
+    }
+
+    #[test]
+    fn describes_the_hue_and_saturation_of_any_color() {
+        for (command, expected) in [
+            (
+                LightCommand {
+                    light_color: Some(String::from("#f00")),
+                    ..Default::default()
+                },
+                (Some(0), Some(100)),
+            ),
+            (
+                LightCommand {
+                    hue: Some(360),
+                    ..Default::default()
+                },
+                (Some(0), Some(100)),
+            ),
+            (
+                LightCommand {
+                    hue: Some(120),
+                    saturation: Some(40),
+                    ..Default::default()
+                },
+                (Some(120), Some(40)),
+            ),
+            (
+                LightCommand {
+                    color_temperature: Some(2_700),
+                    ..Default::default()
+                },
+                (Some(29), Some(66)),
+            ),
+            (
+                LightCommand {
+                    light_color: Some(String::from("blurple")),
+                    ..Default::default()
+                },
+                (None, None),
+            ),
+            (
+                LightCommand {
+                    power: Some(true),
+                    ..Default::default()
+                },
+                (None, None),
+            ),
+        ] {
+            let metadata = command.metadata();
+            assert_eq!((metadata.hue, metadata.saturation), expected, "{command:?}");
+            assert_eq!(metadata.light_color, command.light_color);
+            assert_eq!(metadata.color_temperature, command.color_temperature);
+        }
+
+        let metadata = LightCommand {
+            power: Some(false),
+            brightness: Some(30),
+            transition_time: Some(5),
+            ..Default::default()
+        }
+        .metadata();
+        assert_eq!(metadata.power, Some(false));
+        assert_eq!(metadata.brightness, Some(30));
+        assert_eq!(metadata.transition_time, Some(5));
+    }
+}
@@ -0,0 +1,77 @@
+use std::cell::RefCell;
+
+use ic_cdk::api::stable::{stable64_read, stable64_size};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl,
+};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// The magic bytes the memory manager writes at the beginning of the stable memory.
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+pub const HEAP_STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+/// Stored the devices of all the environments together until version 2 of the state, see [crate::migrations].
+pub const WOT_DEVICES_MEMORY_ID: MemoryId = MemoryId::new(1);
+pub const THING_DESCRIPTIONS_MEMORY_ID: MemoryId = MemoryId::new(2);
+pub const SCHEDULED_COMMANDS_MEMORY_ID: MemoryId = MemoryId::new(3);
+pub const RUNNING_COMMANDS_MEMORY_ID: MemoryId = MemoryId::new(4);
+pub const FINISHED_COMMANDS_MEMORY_ID: MemoryId = MemoryId::new(5);
+pub const ENVIRONMENTS_ACL_MEMORY_ID: MemoryId = MemoryId::new(6);
+pub const DEVICE_ENVIRONMENTS_MEMORY_ID: MemoryId = MemoryId::new(7);
+pub const ENVIRONMENT_DEVICES_MEMORY_ID: MemoryId = MemoryId::new(8);
+pub const SCHEDULED_BY_TIMESTAMP_MEMORY_ID: MemoryId = MemoryId::new(9);
+pub const FINISHED_BY_TIMESTAMP_MEMORY_ID: MemoryId = MemoryId::new(10);
+pub const COMMANDS_BY_DEVICE_MEMORY_ID: MemoryId = MemoryId::new(11);
+pub const COMMANDS_BY_SENDER_MEMORY_ID: MemoryId = MemoryId::new(12);
+pub const QUEUED_COMMANDS_MEMORY_ID: MemoryId = MemoryId::new(13);
+pub const SCHEDULED_BY_SENDER_MEMORY_ID: MemoryId = MemoryId::new(14);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get_memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Returns true if the stable memory contains the CBOR dump of the state written by the previous versions,
+/// which must be read before the memory manager takes over the stable memory.
+pub fn is_legacy_stable_memory() -> bool {
+    if stable64_size() == 0 {
+        return false;
+    }
+
+    let mut magic = [0; 3];
+    stable64_read(0, &mut magic);
+
+    &magic != MEMORY_MANAGER_MAGIC
+}
+
+/// Implements [ic_stable_structures::Storable] for types that can be serialized with serde, encoding them as CBOR.
+///
+/// New fields must have a `#[serde(default)]`, so that the values stored by the previous versions can still be decoded.
+macro_rules! impl_cbor_storable {
+    ($($t:ty),*) => {
+        $(
+            impl ic_stable_structures::Storable for $t {
+                fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                    let mut bytes = vec![];
+                    ciborium::ser::into_writer(self, &mut bytes).expect("failed to encode value");
+                    std::borrow::Cow::Owned(bytes)
+                }
+
+                fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+                    ciborium::de::from_reader(bytes.as_ref()).expect("failed to decode value")
+                }
+
+                const BOUND: ic_stable_structures::storable::Bound =
+                    ic_stable_structures::storable::Bound::Unbounded;
+            }
+        )*
+    };
+}
+
+pub(crate) use impl_cbor_storable;
@@ -0,0 +1,388 @@
+//! The state schema is versioned, so that each upgrade can bring the stored state to the current version
+//! by running the migrations from the version it was saved with.
+//!
+//! To change the schema, bump [STATE_VERSION] and append the migration from the previous version to [MIGRATIONS].
+
+use std::borrow::Cow;
+
+use ciborium::value::Value;
+use ic_cdk::api::trap;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use omnia_core_sdk::access_key::AccessKeyUID;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::LegacyDeviceCommands,
+    memory::{get_memory, Memory, WOT_DEVICES_MEMORY_ID},
+    rdf::GenericError,
+    utils::log,
+    wot::{Device, DeviceUrl, ThingDescriptions, WotDevices},
+    HeapState, State,
+};
+
+/// The version of the state schema written by this version of the canister.
+///
+/// - 0: the whole state dumped as CBOR to stable memory in `pre_upgrade`.
+/// - 1: the state in stable structures, with the heap fields saved as they are.
+/// - 2: the heap fields saved in a [StateEnvelope], along with the version.
+/// - 3: the devices stored by environment, along with when they have been fetched.
+/// - 4: the indexes of the commands in stable structures.
+pub const STATE_VERSION: u32 = 4;
+
+/// A migration from a version to the next one, taking the heap state saved with the previous version.
+type Migration = fn(&mut State, Value) -> Result<Value, GenericError>;
+
+/// The migrations from version 1 onwards, indexed by the version they migrate from minus one.
+/// Version 0 is migrated by [migrate_v0_to_v1], because it's not stored in stable structures.
+const MIGRATIONS: [Migration; STATE_VERSION as usize - 1] =
+    [migrate_v1_to_v2, migrate_v2_to_v3, migrate_v3_to_v4];
+
+/// The heap fields of the state, along with the version of the schema they were saved with.
+///
+/// The fields are kept as a generic CBOR value, so that they can be decoded even if the schema changed.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StateEnvelope {
+    pub version: u32,
+    pub heap_state: Value,
+}
+
+impl StateEnvelope {
+    pub fn new(heap_state: &HeapState) -> Self {
+        Self {
+            version: STATE_VERSION,
+            heap_state: Value::serialized(heap_state).expect("failed to encode heap state"),
+        }
+    }
+}
+
+impl Storable for StateEnvelope {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(self, &mut bytes).expect("failed to encode state envelope");
+        Cow::Owned(bytes)
+    }
+
+    /// The version 1 saved the heap fields without an envelope.
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let value: Value =
+            ciborium::de::from_reader(bytes.as_ref()).expect("failed to decode state envelope");
+
+        value
+            .deserialized::<StateEnvelope>()
+            .unwrap_or(StateEnvelope {
+                version: 1,
+                heap_state: value,
+            })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The state as it was dumped to stable memory before moving to stable structures.
+///
+/// The older fields are filled with their defaults when decoded.
+#[derive(Deserialize)]
+pub struct LegacyState {
+    wot_devices: WotDevices,
+    #[serde(default)]
+    thing_descriptions: ThingDescriptions,
+    device_commands: LegacyDeviceCommands,
+    last_valid_access_key: Option<AccessKeyUID>,
+}
+
+/// Brings the stored state to [STATE_VERSION], returning the heap fields to load.
+///
+/// Traps if a migration fails, so that the upgrade is rolled back and the previous version keeps running.
+pub fn migrate(state: &mut State, legacy_state: Option<LegacyState>) -> HeapState {
+    let (mut version, mut heap_state) = match legacy_state {
+        Some(legacy_state) => (1, migrate_v0_to_v1(state, legacy_state)),
+        None => {
+            let envelope = state.heap_state.get();
+            (envelope.version, envelope.heap_state.clone())
+        }
+    };
+
+    if version > STATE_VERSION {
+        trap(&format!(
+            "State version {version} is newer than {STATE_VERSION}, downgrades are not supported"
+        ));
+    }
+
+    while version < STATE_VERSION {
+        log(format!(
+            "Migrating state from version {version} to {}...",
+            version + 1
+        ));
+
+        heap_state = MIGRATIONS[version as usize - 1](state, heap_state).unwrap_or_else(|e| {
+            trap(&format!(
+                "Failed to migrate state from version {version}: {e}"
+            ))
+        });
+        version += 1;
+    }
+
+    heap_state
+        .deserialized()
+        .unwrap_or_else(|e| trap(&format!("Failed to decode heap state: {e}")))
+}
+
+/// Moves the state dumped by the previous versions into the stable structures.
+fn migrate_v0_to_v1(state: &mut State, legacy_state: LegacyState) -> Value {
+    log("Migrating the legacy state to stable structures...");
+
+    // stored as in version 1, the next migrations take care of moving them
+    let mut wot_devices = init_wot_devices_v2();
+    for (device_url, device) in legacy_state.wot_devices {
+        wot_devices.insert(device_url, device);
+    }
+    for (device_url, td) in legacy_state.thing_descriptions {
+        state.thing_descriptions.insert(device_url, td);
+    }
+    let commands_settings = state
+        .device_commands
+        .import_legacy(legacy_state.device_commands);
+
+    Value::serialized(&HeapState {
+        last_valid_access_key: legacy_state.last_valid_access_key,
+        commands_settings,
+    })
+    .expect("failed to encode heap state")
+}
+
+/// The heap fields are only wrapped in the envelope, their content is unchanged.
+fn migrate_v1_to_v2(_: &mut State, heap_state: Value) -> Result<Value, GenericError> {
+    Ok(heap_state)
+}
+
+/// Groups the devices by the environment they have been fetched from.
+///
+/// The devices whose environment is unknown are dropped, they'll be stored again when their environment is fetched.
+fn migrate_v2_to_v3(state: &mut State, heap_state: Value) -> Result<Value, GenericError> {
+    let mut wot_devices = init_wot_devices_v2();
+    let device_urls: Vec<DeviceUrl> = wot_devices.iter().map(|(url, _)| url).collect();
+
+    for device_url in device_urls {
+        let Some(device) = wot_devices.remove(&device_url) else {
+            continue;
+        };
+        let Some(environment_uid) = state.access_control.get_device_environment(&device_url) else {
+            log(format!(
+                "Dropping device {device_url} with unknown environment"
+            ));
+            continue;
+        };
+
+        let mut environment = state
+            .environment_devices
+            .get(&environment_uid)
+            .unwrap_or_default();
+        environment.devices.insert(device_url, device);
+        state
+            .environment_devices
+            .insert(environment_uid, environment);
+    }
+
+    Ok(heap_state)
+}
+
+/// Indexes the stored commands, which were indexed on the heap after each upgrade until version 3.
+fn migrate_v3_to_v4(state: &mut State, heap_state: Value) -> Result<Value, GenericError> {
+    let heap_state: HeapState = heap_state.deserialized().map_err(|e| e.to_string())?;
+    // the queues of the devices are created while indexing the scheduled commands
+    state
+        .device_commands
+        .set_settings(heap_state.commands_settings);
+    state.device_commands.build_indexes();
+
+    Value::serialized(&HeapState {
+        last_valid_access_key: heap_state.last_valid_access_key,
+        commands_settings: state.device_commands.get_settings(),
+    })
+    .map_err(|e| e.to_string())
+}
+
+/// The devices of all the environments, as stored until version 2.
+fn init_wot_devices_v2() -> StableBTreeMap<DeviceUrl, Device, Memory> {
+    StableBTreeMap::init(get_memory(WOT_DEVICES_MEMORY_ID))
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+    use ciborium::cbor;
+    use ic_cdk::api::management_canister::http_request::HttpMethod;
+
+    use super::*;
+    use crate::commands::{CommandStatus, CommandsQuery, HistoryQuery};
+
+    const DEVICE_URL: &str = "https://light.example.com";
+    const ENVIRONMENT_UID: &str = "c6a3e9f4-4a57-4c5e-9d0b-6f0e1b2c3d4e";
+
+    fn to_bytes(value: &Value) -> Vec<u8> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(value, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn assert_heap_state(heap_state: &HeapState, expected: Value) {
+        assert_eq!(Value::serialized(heap_state).unwrap(), expected);
+    }
+
+    /// A command as stored by version 0, with a single request and the color as a string.
+    fn legacy_command(schedule_timestamp: u64, status: Value) -> Value {
+        cbor!({
+            "device_url" => DEVICE_URL,
+            "http_arguments" => {
+                "url" => DEVICE_URL,
+                "method" => Value::serialized(&HttpMethod::POST).unwrap(),
+                "headers" => [{ "name" => "Content-Type", "value" => "application/json" }],
+                "body" => null,
+            },
+            "schedule_timestamp" => schedule_timestamp,
+            "sender" => Value::serialized(&Principal::anonymous()).unwrap(),
+            "metadata" => { "light_color" => "#ff0000" },
+            "status" => status,
+        })
+        .unwrap()
+    }
+
+    /// The heap fields as saved by versions 1 and 2.
+    fn heap_state_v1() -> Value {
+        cbor!({
+            "last_valid_access_key" => null,
+            "commands_settings" => {
+                "next_command_id" => 7,
+                "queues" => {
+                    DEVICE_URL => { "interval" => 30_000_000_000_u64, "last_started_timestamp" => 123 },
+                },
+                "history_retention" => { "max_commands" => 50, "max_age" => 1_000 },
+            },
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn migrates_the_legacy_dump() {
+        let fixture = to_bytes(
+            &cbor!({
+                "wot_devices" => {
+                    DEVICE_URL => { "headers" => { "X-Access-Key" => "key" } },
+                },
+                "device_commands" => {
+                    "scheduled_commands" => {
+                        2_000 => legacy_command(2_000, cbor!("Scheduled").unwrap()),
+                    },
+                    "running_commands" => {},
+                    "finished_commands" => {
+                        1_000 => legacy_command(1_000, cbor!({ "Failed" => "HTTP status: 500" }).unwrap()),
+                    },
+                },
+                "last_valid_access_key" => null,
+            })
+            .unwrap(),
+        );
+        let legacy_state: LegacyState = ciborium::de::from_reader(fixture.as_slice()).unwrap();
+
+        let mut state = State::init();
+        let heap_state = migrate(&mut state, Some(legacy_state));
+
+        assert_heap_state(
+            &heap_state,
+            cbor!({
+                "last_valid_access_key" => null,
+                "commands_settings" => {
+                    "next_command_id" => 2_001,
+                    "queues" => {
+                        DEVICE_URL => { "interval" => 15_000_000_000_u64, "last_started_timestamp" => 0 },
+                    },
+                    "history_retention" => { "max_commands" => 1_000, "max_age" => 2_592_000_000_000_000_u64 },
+                },
+            })
+            .unwrap(),
+        );
+
+        // the commands are keyed by their schedule timestamp, which becomes their id
+        state.load_heap_state(heap_state);
+        let scheduled = state.device_commands.get_command(2_000).unwrap();
+        assert_eq!(scheduled.id, 2_000);
+        assert!(matches!(scheduled.status, CommandStatus::Scheduled));
+        assert_eq!(
+            scheduled.metadata.unwrap().light_color.as_deref(),
+            Some("#ff0000")
+        );
+        let finished = state.device_commands.get_command(1_000).unwrap();
+        assert!(matches!(finished.status, CommandStatus::Failed(e) if e == "HTTP status: 500"));
+        // the commands are indexed
+        let view = state
+            .device_commands
+            .get_commands_view(&CommandsQuery::default(), |_| true);
+        assert_eq!(view.scheduled_commands[0].0, 2_000);
+        assert_eq!(view.finished_commands[0].0, 1_000);
+        let page = state.device_commands.get_history(
+            &HistoryQuery {
+                device_url: Some(DEVICE_URL.to_string()),
+                ..Default::default()
+            },
+            |_| true,
+        );
+        assert_eq!(page.commands[0].id, 1_000);
+
+        // the environment of the devices was not recorded yet
+        assert!(state.environment_devices.is_empty());
+        assert!(init_wot_devices_v2().is_empty());
+    }
+
+    #[test]
+    fn migrates_the_bare_heap_state() {
+        let fixture = to_bytes(&heap_state_v1());
+        let envelope = StateEnvelope::from_bytes(Cow::Owned(fixture));
+        assert_eq!(envelope.version, 1);
+
+        let mut state = State::init();
+        state.heap_state.set(envelope).unwrap();
+
+        assert_heap_state(&migrate(&mut state, None), heap_state_v1());
+    }
+
+    #[test]
+    fn migrates_the_envelope() {
+        let fixture =
+            to_bytes(&cbor!({ "version" => 2, "heap_state" => heap_state_v1() }).unwrap());
+        let envelope = StateEnvelope::from_bytes(Cow::Owned(fixture));
+        assert_eq!(envelope.version, 2);
+
+        let mut state = State::init();
+        state.heap_state.set(envelope).unwrap();
+        let mut device = Device::default();
+        device
+            .headers
+            .insert("X-Access-Key".to_string(), "key".to_string());
+        init_wot_devices_v2().insert(DEVICE_URL.to_string(), device);
+        state
+            .access_control
+            .set_device_environment(DEVICE_URL.to_string(), ENVIRONMENT_UID.to_string());
+
+        assert_heap_state(&migrate(&mut state, None), heap_state_v1());
+
+        // the devices are moved to their environment
+        assert!(init_wot_devices_v2().is_empty());
+        let environment = state
+            .environment_devices
+            .get(&ENVIRONMENT_UID.to_string())
+            .unwrap();
+        assert_eq!(
+            environment.devices[DEVICE_URL].headers["X-Access-Key"],
+            "key"
+        );
+    }
+
+    #[test]
+    fn decodes_the_current_envelope() {
+        let envelope = StateEnvelope::new(&HeapState::default());
+        let decoded = StateEnvelope::from_bytes(envelope.to_bytes());
+
+        assert_eq!(decoded.version, STATE_VERSION);
+        assert_eq!(decoded.heap_state, envelope.heap_state);
+    }
+}
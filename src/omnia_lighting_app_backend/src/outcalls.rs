@@ -1,12 +1,11 @@
 use candid::Nat;
 use ic_cdk::{
-    api::{
-        management_canister::http_request::{HttpResponse, TransformArgs},
-        print,
-    },
+    api::management_canister::http_request::{HttpResponse, TransformArgs},
     query,
 };
 
+use crate::utils::log;
+
 /// Use this response transformer when parsing a response from the WoT device.
 ///
 /// TODO: move this transformer to the SDK
@@ -23,14 +22,14 @@ fn transform_device_response(raw: TransformArgs) -> HttpResponse {
     } else if res.status == Nat::from(401) {
         // this is the case when the access key is invalid
         // the caller needs to request a new access key, so we have to pass the request to caller
-        print(format!(
+        log(format!(
             "transform_device_response: Received 401 from HTTPS outcall: body: {}",
             String::from_utf8(raw.response.body).expect("Failed to parse the response body"),
         ));
 
         res.body = vec![];
     } else {
-        print(format!(
+        log(format!(
             "transform_device_response: Received an error from HTTPS outcall: status: {}, body: {}",
             raw.response.status,
             String::from_utf8(raw.response.body).expect("Failed to parse the response body"),
@@ -38,3 +37,23 @@ fn transform_device_response(raw: TransformArgs) -> HttpResponse {
     }
     res
 }
+
+/// Use this response transformer when fetching the Thing Description of a WoT device.
+///
+/// Headers are stripped, so that all the replicas agree on the response.
+#[query]
+fn transform_thing_description_response(raw: TransformArgs) -> HttpResponse {
+    #[allow(clippy::cmp_owned)]
+    if raw.response.status != Nat::from(200) {
+        log(format!(
+            "transform_thing_description_response: Received an error from HTTPS outcall: status: {}",
+            raw.response.status,
+        ));
+    }
+
+    HttpResponse {
+        status: raw.response.status,
+        body: raw.response.body,
+        ..Default::default()
+    }
+}
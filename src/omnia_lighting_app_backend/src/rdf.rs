@@ -2,39 +2,161 @@ use std::collections::BTreeMap;
 
 use ic_cdk::api::call::call;
 use omnia_core_sdk::utils::get_omnia_backend_canister_id;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Number, Value};
 
-use crate::wot::{DeviceHeaders, WotDevices};
+use self::query::SelectQuery;
+use crate::utils::log;
+
+pub mod escape;
+pub mod query;
 
 pub type GenericError = String;
 
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
-struct RdfQueryHead {
-    vars: Vec<String>,
+const XSD: &str = "http://www.w3.org/2001/XMLSchema#";
+
+/// An RDF term bound to a variable, as defined by the
+/// [SPARQL 1.1 Query Results JSON Format](https://www.w3.org/TR/sparql11-results-json/#select-encode-terms).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RdfTerm {
+    Uri {
+        value: String,
+    },
+    /// Some databases still use the `typed-literal` type of the SPARQL 1.0 format.
+    #[serde(alias = "typed-literal")]
+    Literal {
+        value: String,
+        #[serde(default)]
+        datatype: Option<String>,
+        #[serde(rename = "xml:lang", default)]
+        lang: Option<String>,
+    },
+    Bnode {
+        value: String,
+    },
+}
+
+/// The terms bound to the variables of a solution. The variables that are not bound, like the `OPTIONAL` ones, are missing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RdfBinding(BTreeMap<String, RdfTerm>);
+
+impl RdfTerm {
+    /// Returns the term as JSON, converting the numeric and boolean literals to the JSON types.
+    ///
+    /// The other terms are returned as strings, the IRIs and blank nodes without the brackets.
+    fn to_json(&self) -> Value {
+        match self {
+            RdfTerm::Uri { value } | RdfTerm::Bnode { value } => Value::String(value.clone()),
+            RdfTerm::Literal {
+                value,
+                datatype: Some(datatype),
+                ..
+            } => {
+                let json = match datatype.strip_prefix(XSD) {
+                    Some("boolean") => match value.as_str() {
+                        "true" | "1" => Some(Value::Bool(true)),
+                        "false" | "0" => Some(Value::Bool(false)),
+                        _ => None,
+                    },
+                    Some(
+                        "integer" | "int" | "long" | "short" | "byte" | "nonNegativeInteger"
+                        | "positiveInteger" | "unsignedInt" | "unsignedLong" | "unsignedShort"
+                        | "unsignedByte",
+                    ) => value
+                        .parse::<i64>()
+                        .ok()
+                        .map(Number::from)
+                        .or_else(|| value.parse::<u64>().ok().map(Number::from))
+                        .map(Value::Number),
+                    Some("decimal" | "double" | "float") => value
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(Number::from_f64)
+                        .map(Value::Number),
+                    _ => None,
+                };
+                // a malformed number is kept as a string, so that the row type decides what to do with it
+                json.unwrap_or_else(|| Value::String(value.clone()))
+            }
+            RdfTerm::Literal { value, .. } => Value::String(value.clone()),
+        }
+    }
+}
+
+impl RdfBinding {
+    /// Deserializes the binding into a row type, whose fields are named after the variables.
+    ///
+    /// The variables that are not bound are missing, so they must be `Option` or have a default in the row type.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, GenericError> {
+        let row = self
+            .0
+            .iter()
+            .map(|(var, term)| (var.clone(), term.to_json()))
+            .collect();
+
+        serde_json::from_value(Value::Object(row)).map_err(|e| e.to_string())
+    }
 }
 
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
-struct RdfQueryGenericBindingContent {
-    r#type: String,
-    value: String,
+/// The results of a `SELECT` query.
+#[derive(Clone, Debug, Default)]
+pub struct RdfQueryResults {
+    /// The solutions in the order they were returned, with an error for the ones that couldn't be parsed,
+    /// so that a malformed solution doesn't fail the whole result.
+    pub rows: Vec<Result<RdfBinding, GenericError>>,
 }
 
-type RdfQueryGenericBinding = BTreeMap<String, RdfQueryGenericBindingContent>;
+#[derive(Deserialize)]
+struct RawQueryBindings {
+    bindings: Vec<Value>,
+}
 
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
-struct RdfQueryResults {
-    bindings: Vec<RdfQueryGenericBinding>,
+/// The bindings are kept as generic JSON, so that a malformed one doesn't prevent parsing the others.
+///
+/// The results are required, so that a document that isn't a result, like an error, is not read as no results.
+#[derive(Deserialize)]
+struct RawQueryResult {
+    results: RawQueryBindings,
 }
 
-#[derive(Default, Clone, Debug, Serialize, Deserialize)]
-struct RdfQueryResult {
-    head: RdfQueryHead,
-    results: RdfQueryResults,
+/// Parses the JSON results of a `SELECT` query.
+///
+/// Returns an error only if the document itself is malformed, the malformed solutions are reported in [RdfQueryResults::rows].
+pub fn parse_query_results(body: &[u8]) -> Result<RdfQueryResults, GenericError> {
+    let raw = serde_json::from_slice::<RawQueryResult>(body)
+        .map_err(|e| format!("Invalid SPARQL JSON results: {e}"))?;
+
+    Ok(RdfQueryResults {
+        rows: raw
+            .results
+            .bindings
+            .into_iter()
+            .map(parse_binding)
+            .collect(),
+    })
+}
+
+fn parse_binding(binding: Value) -> Result<RdfBinding, GenericError> {
+    let Value::Object(terms) = binding else {
+        return Err(format!("Binding is not an object: {binding}"));
+    };
+
+    terms
+        .into_iter()
+        .map(|(var, term)| {
+            serde_json::from_value::<RdfTerm>(term)
+                .map(|term| (var.clone(), term))
+                .map_err(|e| format!("Invalid term for variable ?{var}: {e}"))
+        })
+        .collect::<Result<_, _>>()
+        .map(RdfBinding)
 }
 
 /// RDF database graph prefixes:
 /// - **omnia**: <http://rdf.omnia-iot.com#>
 /// - **rdf**: <http://www.w3.org/1999/02/22-rdf-syntax-ns#>
+/// - **rdfs**: <http://www.w3.org/2000/01/rdf-schema#>
 /// - **saref**: <https://saref.etsi.org/core/>
 /// - **bot**: <https://w3id.org/bot#>
 /// - **http**: <https://www.w3.org/2011/http#>
@@ -46,6 +168,7 @@ const PREFIXES: &str = r#"
 PREFIX omnia: <http://rdf.omnia-iot.com#>
 # Third parties
 PREFIX rdf: <http://www.w3.org/1999/02/22-rdf-syntax-ns#>
+PREFIX rdfs: <http://www.w3.org/2000/01/rdf-schema#>
 PREFIX saref: <https://saref.etsi.org/core/>
 PREFIX bot: <https://w3id.org/bot#>
 PREFIX http: <https://www.w3.org/2011/http#>
@@ -60,9 +183,73 @@ fn build_query(q: &str) -> String {
     query
 }
 
-/// Send query to RDF database using the HTTP outcall.
-pub async fn send_query(q: String) -> Result<WotDevices, GenericError> {
-    let sparql_query = build_query(&q);
+/// Expands a name prefixed with one of the [PREFIXES] to its full IRI, returning the other names as they are.
+///
+/// Example: `saref:OnOffCommand` is expanded to `https://saref.etsi.org/core/OnOffCommand`.
+pub fn expand_iri(name: &str) -> String {
+    escape::prefixed_name(name)
+        .ok()
+        .and_then(|_| {
+            let (prefix, local) = name.split_once(':')?;
+            PREFIXES
+                .lines()
+                .filter_map(|line| line.strip_prefix("PREFIX "))
+                .filter_map(|declaration| declaration.split_once(": <"))
+                .find(|(declared, _)| *declared == prefix)
+                .map(|(_, namespace)| format!("{}{local}", namespace.trim_end_matches('>')))
+        })
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// The solutions of a query deserialized into a row type.
+#[derive(Debug)]
+pub struct QueryRows<T> {
+    pub rows: Vec<T>,
+    /// The solutions that couldn't be parsed or deserialized, with their position in the results.
+    pub errors: Vec<(usize, GenericError)>,
+}
+
+impl<T> QueryRows<T> {
+    /// Deserializes the solutions into a row type, see [RdfBinding::deserialize].
+    pub fn from_results(results: RdfQueryResults) -> Self
+    where
+        T: DeserializeOwned,
+    {
+        let mut rows = vec![];
+        let mut errors = vec![];
+        for (i, binding) in results.rows.into_iter().enumerate() {
+            match binding.and_then(|binding| binding.deserialize()) {
+                Ok(row) => rows.push(row),
+                Err(e) => errors.push((i, e)),
+            }
+        }
+
+        Self { rows, errors }
+    }
+
+    /// Returns the rows, or an error if any solution is malformed, for the queries whose results must be complete.
+    pub fn into_complete(self) -> Result<Vec<T>, GenericError> {
+        if self.errors.is_empty() {
+            return Ok(self.rows);
+        }
+
+        let errors: Vec<String> = self
+            .errors
+            .iter()
+            .map(|(i, e)| format!("result {i}: {e}"))
+            .collect();
+        Err(format!("Malformed query results: {}", errors.join("; ")))
+    }
+}
+
+/// Send query to RDF database using the HTTP outcall, deserializing each solution into a row type.
+///
+/// The solutions that can't be deserialized are returned in [QueryRows::errors], the caller decides what to do with them.
+pub async fn send_query<T: DeserializeOwned>(
+    query: &SelectQuery,
+) -> Result<QueryRows<T>, GenericError> {
+    let sparql_query = build_query(&query.build()?);
+    log(format!("Query: {}", sparql_query));
 
     let (rdf_db_query_result,): (Result<Vec<u8>, GenericError>,) = call(
         get_omnia_backend_canister_id(),
@@ -72,33 +259,216 @@ pub async fn send_query(q: String) -> Result<WotDevices, GenericError> {
     .await
     .map_err(|e| format!("Rejection code: {:?}, error {}", e.0, e.1))?;
 
-    match rdf_db_query_result {
-        Ok(result) => parse_rdf_json_response(result),
-        Err(err) => Err(err),
-    }
+    let results = parse_query_results(&rdf_db_query_result?)?;
+
+    Ok(QueryRows::from_results(results))
 }
 
-/// Parse the RDF JSON response into a map of device URLs and their headers.
-///
-/// NOTE: this is specific to the RDF database query for devices.
-pub fn parse_rdf_json_response(body: Vec<u8>) -> Result<WotDevices, String> {
-    let json = serde_json::from_slice::<RdfQueryResult>(&body).map_err(|e| e.to_string())?;
-    let mut r: WotDevices = BTreeMap::new();
-
-    for binding in json.results.bindings {
-        // TODO: handle unwraps
-        let device_url = binding.get("device").unwrap().value.clone();
-        let header_name = binding.get("headerName").unwrap().value.clone();
-        let header_value = binding.get("headerValue").unwrap().value.clone();
-
-        r.entry(device_url)
-            .and_modify(|e| {
-                e.headers.insert(header_name.clone(), header_value.clone());
-            })
-            .or_insert(DeviceHeaders {
-                headers: BTreeMap::from([(header_name, header_value)]),
-            });
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Row {
+        device: String,
+        name: Option<String>,
+    }
+
+    fn parse(results: Value) -> Result<RdfQueryResults, GenericError> {
+        parse_query_results(results.to_string().as_bytes())
     }
 
-    Ok(r)
+    fn literal(value: &str, datatype: &str) -> RdfTerm {
+        RdfTerm::Literal {
+            value: value.to_string(),
+            datatype: Some(format!("{XSD}{datatype}")),
+            lang: None,
+        }
+    }
+
+    #[test]
+    fn parses_the_term_types() {
+        let results = parse(json!({
+            "head": { "vars": ["s", "o", "b", "t", "l"] },
+            "results": { "bindings": [{
+                "s": { "type": "uri", "value": "https://light.example.com" },
+                "o": { "type": "literal", "value": "Lamp" },
+                "b": { "type": "bnode", "value": "b0" },
+                "t": { "type": "typed-literal", "value": "1", "datatype": format!("{XSD}integer") },
+                "l": { "type": "literal", "value": "Lampe", "xml:lang": "fr" },
+            }] },
+        }))
+        .unwrap();
+
+        let binding = results.rows[0].clone().unwrap();
+        assert_eq!(
+            binding.0["s"],
+            RdfTerm::Uri {
+                value: "https://light.example.com".to_string()
+            }
+        );
+        assert_eq!(
+            binding.0["o"],
+            RdfTerm::Literal {
+                value: "Lamp".to_string(),
+                datatype: None,
+                lang: None,
+            }
+        );
+        assert_eq!(
+            binding.0["b"],
+            RdfTerm::Bnode {
+                value: "b0".to_string()
+            }
+        );
+        assert_eq!(binding.0["t"], literal("1", "integer"));
+        assert_eq!(
+            binding.0["l"],
+            RdfTerm::Literal {
+                value: "Lampe".to_string(),
+                datatype: None,
+                lang: Some("fr".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn converts_the_literals_to_json() {
+        for (term, expected) in [
+            (literal("42", "integer"), json!(42)),
+            (literal("-1", "int"), json!(-1)),
+            (
+                literal("18446744073709551615", "unsignedLong"),
+                json!(u64::MAX),
+            ),
+            (literal("0.5", "decimal"), json!(0.5)),
+            (literal("true", "boolean"), json!(true)),
+            (literal("0", "boolean"), json!(false)),
+            // the malformed values are kept as they are
+            (literal("forty-two", "integer"), json!("forty-two")),
+            (literal("NaN", "double"), json!("NaN")),
+            (literal("yes", "boolean"), json!("yes")),
+            (literal("2023-07-13", "date"), json!("2023-07-13")),
+            (
+                RdfTerm::Literal {
+                    value: "42".to_string(),
+                    datatype: None,
+                    lang: Some("en".to_string()),
+                },
+                json!("42"),
+            ),
+            (
+                RdfTerm::Uri {
+                    value: "https://light.example.com".to_string(),
+                },
+                json!("https://light.example.com"),
+            ),
+        ] {
+            assert_eq!(term.to_json(), expected, "{term:?}");
+        }
+    }
+
+    #[test]
+    fn leaves_the_unbound_variables_missing() {
+        let results = parse(json!({
+            "results": { "bindings": [
+                {
+                    "device": { "type": "uri", "value": "https://light.example.com" },
+                    "name": { "type": "literal", "value": "Lamp" },
+                },
+                { "device": { "type": "uri", "value": "https://plug.example.com" } },
+            ] },
+        }))
+        .unwrap();
+
+        let rows = QueryRows::<Row>::from_results(results)
+            .into_complete()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                Row {
+                    device: "https://light.example.com".to_string(),
+                    name: Some("Lamp".to_string()),
+                },
+                Row {
+                    device: "https://plug.example.com".to_string(),
+                    name: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_the_malformed_bindings_among_the_good_ones() {
+        let results = parse(json!({
+            "results": { "bindings": [
+                { "device": { "type": "uri", "value": "https://light.example.com" } },
+                { "device": { "type": "unknown", "value": "x" } },
+                "not a binding",
+                { "name": { "type": "literal", "value": "Lamp" } },
+                { "device": { "type": "uri", "value": "https://plug.example.com" } },
+            ] },
+        }))
+        .unwrap();
+        assert_eq!(results.rows.len(), 5);
+        assert!(results.rows[1].is_err());
+        assert!(results.rows[2].is_err());
+        // the binding is well formed, but the row type requires the device
+        assert!(results.rows[3].is_ok());
+
+        let rows = QueryRows::<Row>::from_results(results);
+        let devices: Vec<&str> = rows.rows.iter().map(|row| row.device.as_str()).collect();
+        assert_eq!(
+            devices,
+            vec!["https://light.example.com", "https://plug.example.com"]
+        );
+        let error_positions: Vec<usize> = rows.errors.iter().map(|(i, _)| *i).collect();
+        assert_eq!(error_positions, vec![1, 2, 3]);
+        assert!(rows.into_complete().is_err());
+    }
+
+    #[test]
+    fn rejects_documents_that_are_not_results() {
+        for document in [
+            json!({ "error": "Query timed out" }),
+            json!({ "results": {} }),
+            json!({ "results": { "bindings": {} } }),
+            json!([]),
+        ] {
+            assert!(parse(document.clone()).is_err(), "{document}");
+        }
+        assert!(parse_query_results(b"not json").is_err());
+
+        let empty = parse(json!({ "results": { "bindings": [] } })).unwrap();
+        assert!(empty.rows.is_empty());
+    }
+
+    #[test]
+    fn expands_the_declared_prefixes() {
+        for (name, expected) in [
+            (
+                "saref:OnOffCommand",
+                "https://saref.etsi.org/core/OnOffCommand",
+            ),
+            (
+                "omnia:SetPowerCommand",
+                "http://rdf.omnia-iot.com#SetPowerCommand",
+            ),
+            (
+                "https://saref.etsi.org/core/OnOffCommand",
+                "https://saref.etsi.org/core/OnOffCommand",
+            ),
+            (
+                "http://rdf.omnia-iot.com#SetPowerCommand",
+                "http://rdf.omnia-iot.com#SetPowerCommand",
+            ),
+            ("unknown:Command", "unknown:Command"),
+            ("Command", "Command"),
+        ] {
+            assert_eq!(expand_iri(name), expected, "{name}");
+        }
+    }
 }
@@ -0,0 +1,560 @@
+//! Escaping and validation of the values interpolated in the SPARQL queries,
+//! so that a value coming from a user can't end the term it's in and change the query.
+//!
+//! The values that have a grammar too strict to be escaped, like variables and prefixed names, are validated instead.
+
+use std::fmt::Write;
+
+use super::{GenericError, PREFIXES};
+
+/// Returns the variable with its leading `?`.
+///
+/// Only the ASCII subset of the SPARQL `VARNAME` grammar is accepted.
+pub fn var(name: &str) -> Result<String, GenericError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("Invalid variable name: {name:?}"));
+    }
+
+    Ok(format!("?{name}"))
+}
+
+/// Returns the absolute IRI between angle brackets,
+/// percent-encoding the characters that are not allowed in an `IRIREF` and the `%` that don't start an encoding.
+pub fn iri(iri: &str) -> Result<String, GenericError> {
+    if !has_scheme(iri) {
+        return Err(format!("IRI {iri:?} is not absolute"));
+    }
+
+    let mut out = String::from("<");
+    for (i, c) in iri.char_indices() {
+        match c {
+            '\u{00}'..='\u{20}' | '<' | '>' | '"' | '{' | '}' | '|' | '^' | '`' | '\\' => {
+                percent_encode(c, &mut out)
+            }
+            '%' if !is_percent_encoding(&iri[i..]) => percent_encode(c, &mut out),
+            c => out.push(c),
+        }
+    }
+    out.push('>');
+
+    Ok(out)
+}
+
+/// Returns the URN with the namespace identifier and the namespace specific string, as defined by RFC 8141.
+///
+/// The characters of the namespace specific string that are not allowed in it are percent-encoded,
+/// including `?` and `#` that would start the other components of the URN.
+pub fn urn(nid: &str, nss: &str) -> Result<String, GenericError> {
+    let is_valid_nid = (2..=32).contains(&nid.len())
+        && nid.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !nid.starts_with('-')
+        && !nid.ends_with('-');
+    if !is_valid_nid {
+        return Err(format!("Invalid URN namespace identifier: {nid:?}"));
+    }
+    if nss.is_empty() {
+        return Err("The URN namespace specific string can't be empty".to_string());
+    }
+
+    let mut out = format!("urn:{nid}:");
+    for (i, c) in nss.char_indices() {
+        match c {
+            c if c.is_ascii_alphanumeric() => out.push(c),
+            // unreserved, sub-delims, ":", "@" and "/"
+            '-' | '.' | '_' | '~' | '!' | '$' | '&' | '\'' | '(' | ')' | '*' | '+' | ',' | ';'
+            | '=' | ':' | '@' | '/' => out.push(c),
+            '%' if is_percent_encoding(&nss[i..]) => out.push(c),
+            c => percent_encode(c, &mut out),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Returns the prefixed name if its prefix is declared in [PREFIXES].
+///
+/// Only the ASCII subset of the SPARQL `PrefixedName` grammar is accepted, without escapes in the local part.
+pub fn prefixed_name(name: &str) -> Result<String, GenericError> {
+    let invalid = || format!("Invalid prefixed name: {name:?}");
+
+    let (prefix, local) = name.split_once(':').ok_or_else(invalid)?;
+
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    let is_valid_local = local.chars().all(|c| is_name_char(c) || c == '.')
+        && !local.starts_with(['.', '-'])
+        && !local.ends_with('.');
+    if !is_valid_local {
+        return Err(invalid());
+    }
+
+    let is_declared = PREFIXES
+        .lines()
+        .filter_map(|line| line.strip_prefix("PREFIX "))
+        .filter_map(|declaration| declaration.split_once(':'))
+        .any(|(declared, _)| declared == prefix);
+    if !is_declared {
+        return Err(format!("Prefix {prefix:?} is not declared"));
+    }
+
+    Ok(name.to_string())
+}
+
+/// Returns the string between double quotes, escaping the characters that are not allowed in a `STRING_LITERAL2`.
+///
+/// Every backslash is escaped, so even if the database expands the `\u` sequences before parsing the query
+/// a quote can only come out escaped.
+pub fn literal(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0C}' => out.push_str("\\f"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+/// Returns the language tag with its leading `@`, if it matches the SPARQL `LANGTAG` grammar.
+pub fn lang_tag(lang: &str) -> Result<String, GenericError> {
+    let mut subtags = lang.split('-');
+    let is_valid = subtags
+        .next()
+        .is_some_and(|s| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphabetic()))
+        && subtags.all(|s| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric()));
+    if !is_valid {
+        return Err(format!("Invalid language tag: {lang:?}"));
+    }
+
+    Ok(format!("@{lang}"))
+}
+
+/// Whether the IRI starts with a scheme, as defined by RFC 3987.
+fn has_scheme(iri: &str) -> bool {
+    let Some((scheme, _)) = iri.split_once(':') else {
+        return false;
+    };
+
+    scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+}
+
+/// Whether the string starts with a `%` followed by two hex digits.
+fn is_percent_encoding(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    bytes.len() >= 3
+        && bytes[0] == b'%'
+        && bytes[1].is_ascii_hexdigit()
+        && bytes[2].is_ascii_hexdigit()
+}
+
+fn percent_encode(c: char, out: &mut String) {
+    let mut buf = [0; 4];
+    for byte in c.encode_utf8(&mut buf).bytes() {
+        write!(out, "%{byte:02X}").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdf::query::{SelectQuery, Term};
+
+    /// How many inputs are generated for each property.
+    const CASES: usize = 1_000;
+
+    /// The fragments the inputs are made of, biased towards the ones that could end a term.
+    const FRAGMENTS: &[&str] = &[
+        "a",
+        "Z",
+        "0",
+        "-",
+        " ",
+        "\"",
+        "'",
+        "<",
+        ">",
+        "\\",
+        "{",
+        "}",
+        "(",
+        ")",
+        ".",
+        ";",
+        "|",
+        "^",
+        "`",
+        "@",
+        "#",
+        "?",
+        ":",
+        "/",
+        "%",
+        "%2",
+        "%41",
+        "\n",
+        "\r",
+        "\t",
+        "\u{0}",
+        "\u{7f}",
+        "é",
+        "\u{2028}",
+        "😀",
+        "\\u0022",
+        "\\u003E",
+        "\\U0000007D",
+        "\\\"",
+        "\"\"\"",
+        "^^",
+        "} . ?s ?p ?o . {",
+        "> . ?s ?p ?o . <",
+        "\" . } DROP ALL ; {",
+        "?o",
+        "<http://example.com/>",
+    ];
+
+    /// A xorshift generator, so that the generated inputs are the same at every run.
+    struct Inputs(u64);
+
+    impl Inputs {
+        fn new() -> Self {
+            Self(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn next_string(&mut self) -> String {
+            let len = self.next_u64() % 12;
+            (0..len)
+                .map(|_| FRAGMENTS[(self.next_u64() % FRAGMENTS.len() as u64) as usize])
+                .collect()
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Token {
+        Word(String),
+        Var(String),
+        Iri(String),
+        /// The unescaped value of a string literal.
+        Literal(String),
+        LangTag(String),
+        Datatype,
+        Punctuation(char),
+    }
+
+    /// Splits the query in the tokens of the SPARQL grammar the builder writes,
+    /// failing if a term is not well-formed.
+    fn tokenize(query: &str) -> Result<Vec<Token>, String> {
+        let mut tokens = vec![];
+        let mut chars = query.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                c if c.is_ascii_whitespace() => {}
+                '?' => {
+                    let mut name = String::new();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                        name.push(c);
+                    }
+                    tokens.push(Token::Var(name));
+                }
+                '<' => {
+                    let mut iri = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('>') => break,
+                            Some(
+                                c @ ('\u{00}'..='\u{20}'
+                                | '<'
+                                | '"'
+                                | '{'
+                                | '}'
+                                | '|'
+                                | '^'
+                                | '`'
+                                | '\\'),
+                            ) => return Err(format!("{c:?} in IRI {iri:?}")),
+                            Some(c) => iri.push(c),
+                            None => return Err(format!("Unterminated IRI {iri:?}")),
+                        }
+                    }
+                    tokens.push(Token::Iri(iri));
+                }
+                '"' => {
+                    let mut value = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some('t') => value.push('\t'),
+                                Some('b') => value.push('\u{08}'),
+                                Some('n') => value.push('\n'),
+                                Some('r') => value.push('\r'),
+                                Some('f') => value.push('\u{0C}'),
+                                Some(c @ ('"' | '\'' | '\\')) => value.push(c),
+                                c => return Err(format!("Invalid escape \\{c:?} in literal")),
+                            },
+                            Some(c @ ('\n' | '\r')) => return Err(format!("{c:?} in literal")),
+                            Some(c) => value.push(c),
+                            None => return Err(format!("Unterminated literal {value:?}")),
+                        }
+                    }
+                    tokens.push(Token::Literal(value));
+                }
+                '^' if chars.next_if_eq(&'^').is_some() => tokens.push(Token::Datatype),
+                '@' => {
+                    let mut tag = String::new();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '-') {
+                        tag.push(c);
+                    }
+                    tokens.push(Token::LangTag(tag));
+                }
+                c if c.is_ascii_alphabetic() => {
+                    let mut word = c.to_string();
+                    while let Some(c) = chars.next_if(|c| {
+                        c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ':' | '.')
+                    }) {
+                        word.push(c);
+                    }
+                    tokens.push(Token::Word(word));
+                }
+                '{' | '}' | '.' | '(' | ')' | '|' | '*' => tokens.push(Token::Punctuation(c)),
+                c => return Err(format!("Unexpected {c:?}")),
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    /// Expands the `\u` and `\U` escapes, as the database may do before parsing the query.
+    ///
+    /// Stores with a JavaCC parser only expand the escapes whose backslash is not itself escaped,
+    /// a naive one could expand all of them.
+    fn expand_codepoint_escapes(query: &str, naive: bool) -> String {
+        let mut out = String::new();
+        let mut rest = query;
+
+        while let Some(i) = rest.find('\\') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+
+            let len = match rest.as_bytes().get(1) {
+                Some(b'u') => 4,
+                Some(b'U') => 8,
+                // an escaped backslash can't start a codepoint escape
+                Some(b'\\') if !naive => {
+                    out.push_str("\\\\");
+                    rest = &rest[2..];
+                    continue;
+                }
+                _ => 0,
+            };
+            let c = rest
+                .get(2..2 + len)
+                .filter(|_| len > 0)
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .and_then(char::from_u32);
+            match c {
+                Some(c) => {
+                    out.push(c);
+                    rest = &rest[2 + len..];
+                }
+                None => {
+                    out.push('\\');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(rest);
+
+        out
+    }
+
+    fn percent_decode(s: &str) -> Vec<u8> {
+        let mut out = vec![];
+        let mut i = 0;
+        while i < s.len() {
+            if s.is_char_boundary(i) && is_percent_encoding(&s[i..]) {
+                out.push(u8::from_str_radix(&s[i + 1..i + 3], 16).unwrap());
+                i += 3;
+            } else {
+                out.push(s.as_bytes()[i]);
+                i += 1;
+            }
+        }
+        out
+    }
+
+    /// Builds a query with the term as the object of its only triple pattern,
+    /// returning the tokens the term has been written as.
+    ///
+    /// Panics if the query doesn't parse as that single triple pattern, even after expanding the codepoint escapes.
+    /// When all the escapes are expanded the query may not parse anymore, but it can't parse as another pattern.
+    fn build_with_object(object: Term) -> Result<Vec<Token>, GenericError> {
+        let query = SelectQuery::new(["s"])
+            .triple(Term::var("s"), Term::iri("http://example.com/p"), object)
+            .build()?;
+
+        let mut object_tokens = vec![];
+        for (query, must_parse) in [
+            (expand_codepoint_escapes(&query, true), false),
+            (expand_codepoint_escapes(&query, false), true),
+            (query, true),
+        ] {
+            let mut tokens = match tokenize(&query) {
+                Ok(tokens) => tokens,
+                Err(e) if must_parse => panic!("{e} in query:\n{query}"),
+                Err(_) => continue,
+            };
+            let prefix: Vec<Token> = tokens.drain(..6).collect();
+            assert_eq!(
+                prefix,
+                [
+                    Token::Word("SELECT".to_string()),
+                    Token::Var("s".to_string()),
+                    Token::Word("WHERE".to_string()),
+                    Token::Punctuation('{'),
+                    Token::Var("s".to_string()),
+                    Token::Iri("http://example.com/p".to_string()),
+                ],
+                "query:\n{query}"
+            );
+            assert_eq!(
+                tokens.split_off(tokens.len().saturating_sub(2)),
+                [Token::Punctuation('.'), Token::Punctuation('}')],
+                "query:\n{query}"
+            );
+            object_tokens = tokens;
+        }
+
+        Ok(object_tokens)
+    }
+
+    #[test]
+    fn iris_stay_a_single_iri() {
+        let mut inputs = Inputs::new();
+
+        for _ in 0..CASES {
+            let iri = format!("http://example.com/{}", inputs.next_string());
+            let tokens = build_with_object(Term::iri(iri.clone())).unwrap();
+
+            match tokens.as_slice() {
+                [Token::Iri(escaped)] => assert_eq!(percent_decode(escaped), percent_decode(&iri)),
+                tokens => panic!("IRI {iri:?} written as {tokens:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn iris_without_a_valid_scheme_are_rejected() {
+        let mut inputs = Inputs::new();
+
+        for _ in 0..CASES {
+            let iri = inputs.next_string();
+            match build_with_object(Term::iri(iri.clone())) {
+                Ok(tokens) => assert!(
+                    has_scheme(&iri) && matches!(tokens.as_slice(), [Token::Iri(_)]),
+                    "IRI {iri:?} written as {tokens:?}"
+                ),
+                Err(_) => assert!(!has_scheme(&iri)),
+            }
+        }
+    }
+
+    #[test]
+    fn urns_stay_a_single_iri() {
+        let mut inputs = Inputs::new();
+
+        for _ in 0..CASES {
+            let nss = inputs.next_string();
+            let Ok(term) = Term::urn("example", &nss) else {
+                assert!(nss.is_empty());
+                continue;
+            };
+            let tokens = build_with_object(term).unwrap();
+
+            match tokens.as_slice() {
+                [Token::Iri(urn)] => {
+                    let encoded_nss = urn.strip_prefix("urn:example:").unwrap();
+                    assert_eq!(percent_decode(encoded_nss), percent_decode(&nss));
+                    assert!(!encoded_nss.contains(['?', '#']), "URN {urn:?}");
+                }
+                tokens => panic!("URN with {nss:?} written as {tokens:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_urn_namespaces_are_rejected() {
+        let mut inputs = Inputs::new();
+
+        for _ in 0..CASES {
+            let nid = inputs.next_string();
+            if let Ok(term) = Term::urn(&nid, "nss") {
+                let tokens = build_with_object(term).unwrap();
+                assert_eq!(tokens, [Token::Iri(format!("urn:{nid}:nss"))]);
+            }
+        }
+    }
+
+    #[test]
+    fn literals_stay_a_single_literal() {
+        let mut inputs = Inputs::new();
+
+        for _ in 0..CASES {
+            let value = inputs.next_string();
+
+            let tokens = build_with_object(Term::literal(value.clone())).unwrap();
+            assert_eq!(tokens, [Token::Literal(value.clone())]);
+
+            let tokens = build_with_object(Term::lang_literal(value.clone(), "en-GB")).unwrap();
+            assert_eq!(
+                tokens,
+                [
+                    Token::Literal(value.clone()),
+                    Token::LangTag("en-GB".to_string())
+                ]
+            );
+
+            let datatype = format!("http://example.com/{}", inputs.next_string());
+            let tokens = build_with_object(Term::typed_literal(value.clone(), datatype)).unwrap();
+            assert!(
+                matches!(
+                    tokens.as_slice(),
+                    [Token::Literal(v), Token::Datatype, Token::Iri(_)] if *v == value
+                ),
+                "literal {value:?} written as {tokens:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_lang_tags_are_rejected() {
+        let mut inputs = Inputs::new();
+
+        for _ in 0..CASES {
+            let lang = inputs.next_string();
+            if let Ok(tokens) = build_with_object(Term::lang_literal("value", lang.clone())) {
+                assert_eq!(
+                    tokens,
+                    [Token::Literal("value".to_string()), Token::LangTag(lang)]
+                );
+            }
+        }
+    }
+}
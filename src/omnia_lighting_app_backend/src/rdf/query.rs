@@ -0,0 +1,533 @@
+//! A builder of SPARQL `SELECT` queries, so that the values used in a query are escaped instead of being
+//! interpolated as they are.
+//!
+//! Example:
+//! ```ignore
+//! let query = SelectQuery::new(["device"])
+//!     .triple(Term::from(environment_uuid), Term::prefixed("bot:hasElement"), Term::var("device"))
+//!     .triple(Term::var("device"), Term::prefixed("rdf:type"), Term::prefixed("saref:Device"))
+//!     .limit(10);
+//! ```
+
+use std::fmt::Write;
+
+use uuid::Uuid;
+
+use super::{escape, GenericError};
+
+/// A term of a triple pattern or of an expression.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Term {
+    /// A variable, without the leading `?`.
+    Var(String),
+    /// An absolute IRI, without the angle brackets.
+    /// The characters that are not allowed in it are percent-encoded, see [escape::iri].
+    Iri(String),
+    /// An IRI abbreviated with one of the prefixes declared in [super::PREFIXES], like `saref:Device`.
+    PrefixedName(String),
+    Literal {
+        value: String,
+        /// The absolute IRI of the datatype.
+        datatype: Option<String>,
+        lang: Option<String>,
+    },
+    /// A property path matching any sequence of the predicates, including the empty one,
+    /// like `(bot:hasStorey|bot:hasSpace)*`.
+    ZeroOrMore(Vec<Term>),
+}
+
+impl Term {
+    pub fn var(name: impl Into<String>) -> Self {
+        Term::Var(name.into())
+    }
+
+    pub fn iri(iri: impl Into<String>) -> Self {
+        Term::Iri(iri.into())
+    }
+
+    /// Returns the URN with the namespace identifier and the namespace specific string, see [escape::urn].
+    // the UUIDs are the only URNs in the queries yet, and they don't need to be escaped
+    #[allow(dead_code)]
+    pub fn urn(nid: &str, nss: &str) -> Result<Self, GenericError> {
+        escape::urn(nid, nss).map(Term::Iri)
+    }
+
+    pub fn prefixed(name: impl Into<String>) -> Self {
+        Term::PrefixedName(name.into())
+    }
+
+    // no query matches literals yet
+    #[allow(dead_code)]
+    pub fn literal(value: impl Into<String>) -> Self {
+        Term::Literal {
+            value: value.into(),
+            datatype: None,
+            lang: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn typed_literal(value: impl Into<String>, datatype: impl Into<String>) -> Self {
+        Term::Literal {
+            value: value.into(),
+            datatype: Some(datatype.into()),
+            lang: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn lang_literal(value: impl Into<String>, lang: impl Into<String>) -> Self {
+        Term::Literal {
+            value: value.into(),
+            datatype: None,
+            lang: Some(lang.into()),
+        }
+    }
+
+    fn write(&self, out: &mut String) -> Result<(), GenericError> {
+        match self {
+            Term::Var(name) => write_var(name, out),
+            Term::Iri(iri) => {
+                out.push_str(&escape::iri(iri)?);
+                Ok(())
+            }
+            Term::PrefixedName(name) => {
+                out.push_str(&escape::prefixed_name(name)?);
+                Ok(())
+            }
+            Term::Literal {
+                value,
+                datatype,
+                lang,
+            } => {
+                out.push_str(&escape::literal(value));
+                match (datatype, lang) {
+                    (Some(_), Some(_)) => {
+                        return Err(format!(
+                            "Literal {value:?} can't have both a datatype and a language tag"
+                        ));
+                    }
+                    (Some(datatype), None) => {
+                        out.push_str("^^");
+                        out.push_str(&escape::iri(datatype)?);
+                    }
+                    (None, Some(lang)) => {
+                        out.push_str(&escape::lang_tag(lang)?);
+                    }
+                    (None, None) => {}
+                }
+                Ok(())
+            }
+            Term::ZeroOrMore(predicates) => {
+                if predicates.is_empty() {
+                    return Err("A property path needs at least one predicate".to_string());
+                }
+                out.push('(');
+                for (i, predicate) in predicates.iter().enumerate() {
+                    if i > 0 {
+                        out.push('|');
+                    }
+                    match predicate {
+                        Term::Iri(_) | Term::PrefixedName(_) => predicate.write(out)?,
+                        _ => {
+                            return Err(format!(
+                                "Invalid predicate in property path: {predicate:?}"
+                            ))
+                        }
+                    }
+                }
+                out.push_str(")*");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The UUID is used as its `urn:uuid:` IRI, like the environment UIDs in the RDF database.
+impl From<Uuid> for Term {
+    fn from(uuid: Uuid) -> Self {
+        Term::Iri(uuid.urn().to_string())
+    }
+}
+
+/// An expression of a `FILTER`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
+    Term(Term),
+    NotEqual(Box<Expression>, Box<Expression>),
+}
+
+impl From<Term> for Expression {
+    fn from(term: Term) -> Self {
+        Expression::Term(term)
+    }
+}
+
+impl Expression {
+    pub fn not_equal(left: impl Into<Expression>, right: impl Into<Expression>) -> Self {
+        Expression::NotEqual(Box::new(left.into()), Box::new(right.into()))
+    }
+
+    fn write(&self, out: &mut String) -> Result<(), GenericError> {
+        match self {
+            Expression::Term(term) => term.write(out),
+            Expression::NotEqual(left, right) => write_operation(left, "!=", right, out),
+        }
+    }
+}
+
+fn write_operation(
+    left: &Expression,
+    operator: &str,
+    right: &Expression,
+    out: &mut String,
+) -> Result<(), GenericError> {
+    out.push('(');
+    left.write(out)?;
+    write!(out, " {operator} ").unwrap();
+    right.write(out)?;
+    out.push(')');
+    Ok(())
+}
+
+/// A pattern of the `WHERE` clause.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern {
+    Triple {
+        subject: Term,
+        predicate: Term,
+        object: Term,
+    },
+    Optional(Vec<Pattern>),
+    Filter(Expression),
+    /// Inline data, where a `None` leaves the variable unbound (`UNDEF`).
+    Values {
+        vars: Vec<String>,
+        rows: Vec<Vec<Option<Term>>>,
+    },
+}
+
+impl Pattern {
+    pub fn triple(subject: Term, predicate: Term, object: Term) -> Self {
+        Pattern::Triple {
+            subject,
+            predicate,
+            object,
+        }
+    }
+
+    fn write(&self, out: &mut String) -> Result<(), GenericError> {
+        match self {
+            Pattern::Triple {
+                subject,
+                predicate,
+                object,
+            } => {
+                subject.write(out)?;
+                out.push(' ');
+                predicate.write(out)?;
+                out.push(' ');
+                object.write(out)?;
+                out.push_str(" .\n");
+            }
+            Pattern::Optional(patterns) => {
+                out.push_str("OPTIONAL {\n");
+                for pattern in patterns {
+                    pattern.write(out)?;
+                }
+                out.push_str("}\n");
+            }
+            Pattern::Filter(expression) => {
+                out.push_str("FILTER ");
+                // the expression must be bracketed, unless it's a function call
+                out.push('(');
+                expression.write(out)?;
+                out.push_str(")\n");
+            }
+            Pattern::Values { vars, rows } => {
+                out.push_str("VALUES (");
+                for var in vars {
+                    write_var(var, out)?;
+                    out.push(' ');
+                }
+                out.push_str(") {\n");
+                for row in rows {
+                    if row.len() != vars.len() {
+                        return Err(format!(
+                            "VALUES row has {} terms instead of {}",
+                            row.len(),
+                            vars.len()
+                        ));
+                    }
+                    out.push('(');
+                    for term in row {
+                        match term {
+                            Some(Term::Var(var)) => {
+                                return Err(format!("VALUES can't contain the variable ?{var}"))
+                            }
+                            Some(term) => term.write(out)?,
+                            None => out.push_str("UNDEF"),
+                        }
+                        out.push(' ');
+                    }
+                    out.push_str(")\n");
+                }
+                out.push_str("}\n");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A `SELECT` query, built by chaining its clauses.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SelectQuery {
+    vars: Vec<String>,
+    patterns: Vec<Pattern>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+}
+
+impl SelectQuery {
+    /// Creates a query projecting the given variables, without the leading `?`.
+    pub fn new<V: Into<String>>(vars: impl IntoIterator<Item = V>) -> Self {
+        Self {
+            vars: vars.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn triple(self, subject: Term, predicate: Term, object: Term) -> Self {
+        self.pattern(Pattern::triple(subject, predicate, object))
+    }
+
+    pub fn optional(self, patterns: Vec<Pattern>) -> Self {
+        self.pattern(Pattern::Optional(patterns))
+    }
+
+    // the filters of the queries are in their `OPTIONAL` groups yet
+    #[allow(dead_code)]
+    pub fn filter(self, expression: Expression) -> Self {
+        self.pattern(Pattern::Filter(expression))
+    }
+
+    pub fn values<V: Into<String>>(
+        self,
+        vars: impl IntoIterator<Item = V>,
+        rows: Vec<Vec<Option<Term>>>,
+    ) -> Self {
+        self.pattern(Pattern::Values {
+            vars: vars.into_iter().map(Into::into).collect(),
+            rows,
+        })
+    }
+
+    pub fn pattern(mut self, pattern: Pattern) -> Self {
+        self.patterns.push(pattern);
+        self
+    }
+
+    // the queries return all the matches yet
+    #[allow(dead_code)]
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Returns the SPARQL of the query, without the prefixes.
+    ///
+    /// Returns an error if a term is not valid, see [escape].
+    pub fn build(&self) -> Result<String, GenericError> {
+        let mut out = String::from("SELECT ");
+        if self.vars.is_empty() {
+            out.push_str("* ");
+        }
+        for var in &self.vars {
+            write_var(var, &mut out)?;
+            out.push(' ');
+        }
+
+        out.push_str("WHERE {\n");
+        for pattern in &self.patterns {
+            pattern.write(&mut out)?;
+        }
+        out.push_str("}\n");
+
+        if let Some(limit) = self.limit {
+            writeln!(out, "LIMIT {limit}").unwrap();
+        }
+        if let Some(offset) = self.offset {
+            writeln!(out, "OFFSET {offset}").unwrap();
+        }
+
+        Ok(out)
+    }
+}
+
+fn write_var(name: &str, out: &mut String) -> Result<(), GenericError> {
+    out.push_str(&escape::var(name)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_query() -> SelectQuery {
+        SelectQuery::new(["device", "name"]).triple(
+            Term::var("device"),
+            Term::prefixed("rdf:type"),
+            Term::prefixed("saref:Device"),
+        )
+    }
+
+    #[test]
+    fn builds_the_optional_groups() {
+        let query = device_query()
+            .optional(vec![Pattern::triple(
+                Term::var("device"),
+                Term::prefixed("rdfs:label"),
+                Term::var("name"),
+            )])
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            query,
+            "SELECT ?device ?name WHERE {\n\
+             ?device rdf:type saref:Device .\n\
+             OPTIONAL {\n\
+             ?device rdfs:label ?name .\n\
+             }\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn builds_the_filters() {
+        let query = device_query()
+            .filter(Expression::not_equal(
+                Term::var("device"),
+                Term::iri("https://light.example.com/lamp"),
+            ))
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            query,
+            "SELECT ?device ?name WHERE {\n\
+             ?device rdf:type saref:Device .\n\
+             FILTER ((?device != <https://light.example.com/lamp>))\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn builds_the_values_with_the_unbound_ones() {
+        let query = device_query()
+            .values(
+                ["device", "name"],
+                vec![
+                    vec![
+                        Some(Term::iri("https://light.example.com/lamp")),
+                        Some(Term::literal("Lamp")),
+                    ],
+                    vec![Some(Term::iri("https://light.example.com/strip")), None],
+                ],
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            query,
+            "SELECT ?device ?name WHERE {\n\
+             ?device rdf:type saref:Device .\n\
+             VALUES (?device ?name ) {\n\
+             (<https://light.example.com/lamp> \"Lamp\" )\n\
+             (<https://light.example.com/strip> UNDEF )\n\
+             }\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn rejects_the_values_rows_of_another_length() {
+        let query = device_query().values(
+            ["device", "name"],
+            vec![vec![Some(Term::iri("https://light.example.com/lamp"))]],
+        );
+
+        assert_eq!(
+            query.build(),
+            Err("VALUES row has 1 terms instead of 2".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_the_variables_in_the_values() {
+        let query = device_query().values(["device"], vec![vec![Some(Term::var("other"))]]);
+
+        assert!(query.build().is_err());
+    }
+
+    #[test]
+    fn builds_the_limit_and_the_offset() {
+        let query = SelectQuery::new(Vec::<String>::new())
+            .triple(Term::var("s"), Term::var("p"), Term::var("o"))
+            .limit(10)
+            .offset(20)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            query,
+            "SELECT * WHERE {\n\
+             ?s ?p ?o .\n\
+             }\n\
+             LIMIT 10\n\
+             OFFSET 20\n"
+        );
+    }
+
+    #[test]
+    fn builds_the_zero_or_more_paths() {
+        let query = SelectQuery::new(["device"])
+            .triple(
+                Term::var("zone"),
+                Term::ZeroOrMore(vec![
+                    Term::prefixed("bot:hasStorey"),
+                    Term::iri("https://w3id.org/bot#hasSpace"),
+                ]),
+                Term::var("device"),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            query,
+            "SELECT ?device WHERE {\n\
+             ?zone (bot:hasStorey|<https://w3id.org/bot#hasSpace>)* ?device .\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn rejects_the_invalid_zero_or_more_paths() {
+        for predicates in [vec![], vec![Term::var("p")], vec![Term::literal("p")]] {
+            let query = SelectQuery::new(["o"]).triple(
+                Term::var("s"),
+                Term::ZeroOrMore(predicates),
+                Term::var("o"),
+            );
+
+            assert!(query.build().is_err());
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! The zones of the buildings of an environment, as modeled in the RDF graph with the
+//! [Building Topology Ontology](https://w3id.org/bot): sites contain buildings, which contain storeys,
+//! which contain spaces, which contain the devices.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    rdf::{
+        query::{Pattern, SelectQuery, Term},
+        send_query, GenericError, QueryRows,
+    },
+    utils::log,
+    wot::DeviceUrl,
+};
+
+/// The predicates that link a zone to the zones it contains.
+const CONTAINMENT_PREDICATES: [&str; 3] = [
+    "https://w3id.org/bot#hasBuilding",
+    "https://w3id.org/bot#hasStorey",
+    "https://w3id.org/bot#hasSpace",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum ZoneKind {
+    Site,
+    Building,
+    Storey,
+    /// A room or any other space of a storey.
+    Space,
+}
+
+impl ZoneKind {
+    pub const ALL: [ZoneKind; 4] = [
+        ZoneKind::Site,
+        ZoneKind::Building,
+        ZoneKind::Storey,
+        ZoneKind::Space,
+    ];
+
+    pub fn class_iri(&self) -> &'static str {
+        match self {
+            ZoneKind::Site => "https://w3id.org/bot#Site",
+            ZoneKind::Building => "https://w3id.org/bot#Building",
+            ZoneKind::Storey => "https://w3id.org/bot#Storey",
+            ZoneKind::Space => "https://w3id.org/bot#Space",
+        }
+    }
+
+    pub fn from_class_iri(iri: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.class_iri() == iri)
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
+pub struct Zone {
+    pub iri: String,
+    pub kind: ZoneKind,
+    /// The label of the zone.
+    pub name: Option<String>,
+    /// The IRI of the zone that contains this one, if any.
+    pub parent: Option<String>,
+    /// The devices in the zone, including the ones in the zones it contains.
+    pub devices: BTreeSet<DeviceUrl>,
+}
+
+/// The zones of an environment, by IRI.
+pub type Zones = BTreeMap<String, Zone>;
+
+/// A device in a zone, as returned by the topology query.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ZoneRow {
+    zone: String,
+    zone_type: String,
+    name: Option<String>,
+    parent: Option<String>,
+    device: DeviceUrl,
+}
+
+/// Returns the zones that contain the devices of the environment, directly or through the zones they contain.
+pub async fn fetch_zones(environment_uuid: Uuid) -> Result<Zones, GenericError> {
+    let containment: Vec<Term> = CONTAINMENT_PREDICATES
+        .iter()
+        .map(|iri| Term::iri(*iri))
+        .collect();
+
+    let query = SelectQuery::new(["zone", "zoneType", "name", "parent", "device"])
+        .triple(
+            Term::from(environment_uuid),
+            Term::prefixed("bot:hasElement"),
+            Term::var("device"),
+        )
+        .triple(
+            Term::var("device"),
+            Term::prefixed("rdf:type"),
+            Term::prefixed("saref:Device"),
+        )
+        .triple(
+            Term::var("space"),
+            Term::prefixed("bot:containsElement"),
+            Term::var("device"),
+        )
+        .triple(
+            Term::var("zone"),
+            Term::ZeroOrMore(containment.clone()),
+            Term::var("space"),
+        )
+        .triple(
+            Term::var("zone"),
+            Term::prefixed("rdf:type"),
+            Term::var("zoneType"),
+        )
+        .values(
+            ["zoneType"],
+            ZoneKind::ALL
+                .iter()
+                .map(|kind| vec![Some(Term::iri(kind.class_iri()))])
+                .collect(),
+        )
+        .optional(vec![Pattern::triple(
+            Term::var("zone"),
+            Term::prefixed("rdfs:label"),
+            Term::var("name"),
+        )])
+        .optional(vec![
+            Pattern::triple(
+                Term::var("parent"),
+                Term::var("containment"),
+                Term::var("zone"),
+            ),
+            Pattern::Values {
+                vars: vec!["containment".to_string()],
+                rows: containment
+                    .into_iter()
+                    .map(|term| vec![Some(term)])
+                    .collect(),
+            },
+        ]);
+
+    let QueryRows { rows, errors } = send_query::<ZoneRow>(&query).await?;
+    log(format!("Query result: {:?}", rows));
+    for (i, e) in errors {
+        log(format!("Skipping malformed zone result {i}: {e}"));
+    }
+
+    let mut zones = Zones::new();
+    for row in rows {
+        let Some(kind) = ZoneKind::from_class_iri(&row.zone_type) else {
+            continue;
+        };
+
+        // a zone with more than one value for a field gets the first one returned
+        let zone = zones.entry(row.zone.clone()).or_insert_with(|| Zone {
+            iri: row.zone,
+            kind,
+            name: None,
+            parent: None,
+            devices: BTreeSet::new(),
+        });
+        zone.name = zone.name.take().or(row.name);
+        zone.parent = zone.parent.take().or(row.parent);
+        zone.devices.insert(row.device);
+    }
+
+    Ok(zones)
+}
@@ -1,14 +1,516 @@
-use ic_cdk::print;
-
-/// Accepts 'red', 'green' or 'blue' as string and returns the related hue value
-pub fn get_hue_from_color(color: &str) -> u8 {
-    match color {
-        "red" => 254,
-        "green" => 85,
-        "blue" => 170,
-        _ => {
-            print("Color not supported");
-            0
+use std::str::FromStr;
+
+use crate::rdf::GenericError;
+
+/// Prints the message to the canister logs.
+///
+/// The system API is only available inside a canister, so the unit tests print to the standard output instead.
+pub fn log(message: impl AsRef<str>) {
+    #[cfg(target_arch = "wasm32")]
+    ic_cdk::api::print(message);
+    #[cfg(not(target_arch = "wasm32"))]
+    println!("{}", message.as_ref());
+}
+
+/// The maximum value of hue and saturation in the Zigbee Color Control cluster.
+const ZIGBEE_MAX_VALUE: f64 = 254.0;
+
+/// The color temperature range supported, in Kelvin.
+const MIN_COLOR_TEMPERATURE: u16 = 1_000;
+const MAX_COLOR_TEMPERATURE: u16 = 40_000;
+
+/// A color that can be set on a light.
+///
+/// Accepted string formats:
+/// - RGB hex: `#ff0000` or `#f00`
+/// - named CSS colors: `red`, `cornflowerblue`, ...
+/// - HSV: `hsv(0, 100%, 100%)` (hue in degrees, saturation and value in percent)
+/// - color temperature: `2700K`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightColor {
+    Rgb { r: u8, g: u8, b: u8 },
+    Hsv { h: f64, s: f64, v: f64 },
+    Temperature { kelvin: u16 },
+}
+
+impl FromStr for LightColor {
+    type Err = GenericError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let color = s.trim().to_lowercase();
+
+        if let Some(hex) = color.strip_prefix('#') {
+            return parse_hex(hex).ok_or_else(|| format!("Invalid hex color: {s}"));
+        }
+
+        if let Some(hsv) = color.strip_prefix("hsv(").and_then(|c| c.strip_suffix(')')) {
+            return parse_hsv(hsv).ok_or_else(|| format!("Invalid HSV color: {s}"));
+        }
+
+        if let Some(kelvin) = color
+            .strip_suffix('k')
+            .filter(|k| k.trim().starts_with(|c: char| c.is_ascii_digit()))
+        {
+            let kelvin = kelvin
+                .trim()
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid color temperature: {s}"))?;
+            if !(MIN_COLOR_TEMPERATURE..=MAX_COLOR_TEMPERATURE).contains(&kelvin) {
+                return Err(format!(
+                    "Color temperature must be between {MIN_COLOR_TEMPERATURE}K and {MAX_COLOR_TEMPERATURE}K"
+                ));
+            }
+            return Ok(LightColor::Temperature { kelvin });
+        }
+
+        CSS_COLORS
+            .iter()
+            .find(|(name, _)| *name == color)
+            .map(|(_, rgb)| from_rgb_u32(*rgb))
+            .ok_or_else(|| format!("Color not supported: {s}"))
+    }
+}
+
+impl LightColor {
+    /// Returns the color as RGB.
+    ///
+    /// Color temperatures are approximated with the Tanner Helland algorithm.
+    pub fn to_rgb(self) -> (u8, u8, u8) {
+        match self {
+            LightColor::Rgb { r, g, b } => (r, g, b),
+            LightColor::Hsv { h, s, v } => hsv_to_rgb(h, s, v),
+            LightColor::Temperature { kelvin } => temperature_to_rgb(kelvin),
+        }
+    }
+
+    /// Returns the color as HSV, with hue in degrees and saturation and value in `[0, 1]`.
+    pub fn to_hsv(self) -> (f64, f64, f64) {
+        match self {
+            LightColor::Hsv { h, s, v } => (h, s, v),
+            _ => {
+                let (r, g, b) = self.to_rgb();
+                rgb_to_hsv(r, g, b)
+            }
+        }
+    }
+
+    /// Returns hue and saturation in the Zigbee range `[0, 254]`.
+    pub fn to_hue_saturation(self) -> (u8, u8) {
+        let (h, s, _) = self.to_hsv();
+        (
+            (h / 360.0 * ZIGBEE_MAX_VALUE).round() as u8,
+            (s * ZIGBEE_MAX_VALUE).round() as u8,
+        )
+    }
+
+    /// Returns the CIE 1931 xy chromaticity coordinates of the color, assuming sRGB with D65 white point.
+    pub fn to_cie_xy(self) -> (f64, f64) {
+        let (r, g, b) = self.to_rgb();
+        let (r, g, b) = (srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b));
+
+        let x = 0.4124 * r + 0.3576 * g + 0.1805 * b;
+        let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        let z = 0.0193 * r + 0.1192 * g + 0.9505 * b;
+
+        let sum = x + y + z;
+        if sum == 0.0 {
+            // black has no chromaticity, use the D65 white point
+            return (0.3127, 0.3290);
+        }
+
+        (x / sum, y / sum)
+    }
+
+    /// Returns the color temperature in mireds, if the color is a color temperature.
+    pub fn to_mireds(self) -> Option<u16> {
+        match self {
+            LightColor::Temperature { kelvin } => Some((1_000_000 / kelvin as u32) as u16),
+            _ => None,
+        }
+    }
+}
+
+fn from_rgb_u32(rgb: u32) -> LightColor {
+    LightColor::Rgb {
+        r: (rgb >> 16) as u8,
+        g: (rgb >> 8) as u8,
+        b: rgb as u8,
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<LightColor> {
+    let hex = match hex.len() {
+        // expand the short form, e.g. `f00` -> `ff0000`
+        3 => hex.chars().flat_map(|c| [c, c]).collect::<String>(),
+        6 => hex.to_string(),
+        _ => return None,
+    };
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    u32::from_str_radix(&hex, 16).ok().map(from_rgb_u32)
+}
+
+fn parse_hsv(hsv: &str) -> Option<LightColor> {
+    let components = hsv
+        .split(',')
+        .map(|c| c.trim().trim_end_matches('%').trim().parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+
+    match components[..] {
+        [h, s, v]
+            if (0.0..=360.0).contains(&h)
+                && (0.0..=100.0).contains(&s)
+                && (0.0..=100.0).contains(&v) =>
+        {
+            Some(LightColor::Hsv {
+                h: h % 360.0,
+                s: s / 100.0,
+                v: v / 100.0,
+            })
         }
+        _ => None,
+    }
+}
+
+fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (u8, u8, u8) {
+    let c = v * s;
+    let h = h / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u8 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+
+    (
+        ((r + m) * 255.0).round() as u8,
+        ((g + m) * 255.0).round() as u8,
+        ((b + m) * 255.0).round() as u8,
+    )
+}
+
+fn rgb_to_hsv(r: u8, g: u8, b: u8) -> (f64, f64, f64) {
+    let (r, g, b) = (r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let h = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * (((g - b) / delta).rem_euclid(6.0))
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+
+    (h, s, max)
+}
+
+fn srgb_to_linear(c: u8) -> f64 {
+    let c = c as f64 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn temperature_to_rgb(kelvin: u16) -> (u8, u8, u8) {
+    let t = kelvin as f64 / 100.0;
+
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.698727446 * (t - 60.0).powf(-0.1332047592)
+    };
+    let g = if t <= 66.0 {
+        99.4708025861 * t.ln() - 161.1195681661
+    } else {
+        288.1221695283 * (t - 60.0).powf(-0.0755148492)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.5177312231 * (t - 10.0).ln() - 305.0447927307
+    };
+
+    (
+        r.clamp(0.0, 255.0).round() as u8,
+        g.clamp(0.0, 255.0).round() as u8,
+        b.clamp(0.0, 255.0).round() as u8,
+    )
+}
+
+/// Named colors as defined by CSS Color Module Level 4.
+const CSS_COLORS: &[(&str, u32)] = &[
+    ("aliceblue", 0xf0f8ff),
+    ("antiquewhite", 0xfaebd7),
+    ("aqua", 0x00ffff),
+    ("aquamarine", 0x7fffd4),
+    ("azure", 0xf0ffff),
+    ("beige", 0xf5f5dc),
+    ("bisque", 0xffe4c4),
+    ("black", 0x000000),
+    ("blanchedalmond", 0xffebcd),
+    ("blue", 0x0000ff),
+    ("blueviolet", 0x8a2be2),
+    ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887),
+    ("cadetblue", 0x5f9ea0),
+    ("chartreuse", 0x7fff00),
+    ("chocolate", 0xd2691e),
+    ("coral", 0xff7f50),
+    ("cornflowerblue", 0x6495ed),
+    ("cornsilk", 0xfff8dc),
+    ("crimson", 0xdc143c),
+    ("cyan", 0x00ffff),
+    ("darkblue", 0x00008b),
+    ("darkcyan", 0x008b8b),
+    ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9),
+    ("darkgreen", 0x006400),
+    ("darkgrey", 0xa9a9a9),
+    ("darkkhaki", 0xbdb76b),
+    ("darkmagenta", 0x8b008b),
+    ("darkolivegreen", 0x556b2f),
+    ("darkorange", 0xff8c00),
+    ("darkorchid", 0x9932cc),
+    ("darkred", 0x8b0000),
+    ("darksalmon", 0xe9967a),
+    ("darkseagreen", 0x8fbc8f),
+    ("darkslateblue", 0x483d8b),
+    ("darkslategray", 0x2f4f4f),
+    ("darkslategrey", 0x2f4f4f),
+    ("darkturquoise", 0x00ced1),
+    ("darkviolet", 0x9400d3),
+    ("deeppink", 0xff1493),
+    ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969),
+    ("dimgrey", 0x696969),
+    ("dodgerblue", 0x1e90ff),
+    ("firebrick", 0xb22222),
+    ("floralwhite", 0xfffaf0),
+    ("forestgreen", 0x228b22),
+    ("fuchsia", 0xff00ff),
+    ("gainsboro", 0xdcdcdc),
+    ("ghostwhite", 0xf8f8ff),
+    ("gold", 0xffd700),
+    ("goldenrod", 0xdaa520),
+    ("gray", 0x808080),
+    ("green", 0x008000),
+    ("greenyellow", 0xadff2f),
+    ("grey", 0x808080),
+    ("honeydew", 0xf0fff0),
+    ("hotpink", 0xff69b4),
+    ("indianred", 0xcd5c5c),
+    ("indigo", 0x4b0082),
+    ("ivory", 0xfffff0),
+    ("khaki", 0xf0e68c),
+    ("lavender", 0xe6e6fa),
+    ("lavenderblush", 0xfff0f5),
+    ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd),
+    ("lightblue", 0xadd8e6),
+    ("lightcoral", 0xf08080),
+    ("lightcyan", 0xe0ffff),
+    ("lightgoldenrodyellow", 0xfafad2),
+    ("lightgray", 0xd3d3d3),
+    ("lightgreen", 0x90ee90),
+    ("lightgrey", 0xd3d3d3),
+    ("lightpink", 0xffb6c1),
+    ("lightsalmon", 0xffa07a),
+    ("lightseagreen", 0x20b2aa),
+    ("lightskyblue", 0x87cefa),
+    ("lightslategray", 0x778899),
+    ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xb0c4de),
+    ("lightyellow", 0xffffe0),
+    ("lime", 0x00ff00),
+    ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6),
+    ("magenta", 0xff00ff),
+    ("maroon", 0x800000),
+    ("mediumaquamarine", 0x66cdaa),
+    ("mediumblue", 0x0000cd),
+    ("mediumorchid", 0xba55d3),
+    ("mediumpurple", 0x9370db),
+    ("mediumseagreen", 0x3cb371),
+    ("mediumslateblue", 0x7b68ee),
+    ("mediumspringgreen", 0x00fa9a),
+    ("mediumturquoise", 0x48d1cc),
+    ("mediumvioletred", 0xc71585),
+    ("midnightblue", 0x191970),
+    ("mintcream", 0xf5fffa),
+    ("mistyrose", 0xffe4e1),
+    ("moccasin", 0xffe4b5),
+    ("navajowhite", 0xffdead),
+    ("navy", 0x000080),
+    ("oldlace", 0xfdf5e6),
+    ("olive", 0x808000),
+    ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500),
+    ("orangered", 0xff4500),
+    ("orchid", 0xda70d6),
+    ("palegoldenrod", 0xeee8aa),
+    ("palegreen", 0x98fb98),
+    ("paleturquoise", 0xafeeee),
+    ("palevioletred", 0xdb7093),
+    ("papayawhip", 0xffefd5),
+    ("peachpuff", 0xffdab9),
+    ("peru", 0xcd853f),
+    ("pink", 0xffc0cb),
+    ("plum", 0xdda0dd),
+    ("powderblue", 0xb0e0e6),
+    ("purple", 0x800080),
+    ("rebeccapurple", 0x663399),
+    ("red", 0xff0000),
+    ("rosybrown", 0xbc8f8f),
+    ("royalblue", 0x4169e1),
+    ("saddlebrown", 0x8b4513),
+    ("salmon", 0xfa8072),
+    ("sandybrown", 0xf4a460),
+    ("seagreen", 0x2e8b57),
+    ("seashell", 0xfff5ee),
+    ("sienna", 0xa0522d),
+    ("silver", 0xc0c0c0),
+    ("skyblue", 0x87ceeb),
+    ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090),
+    ("slategrey", 0x708090),
+    ("snow", 0xfffafa),
+    ("springgreen", 0x00ff7f),
+    ("steelblue", 0x4682b4),
+    ("tan", 0xd2b48c),
+    ("teal", 0x008080),
+    ("thistle", 0xd8bfd8),
+    ("tomato", 0xff6347),
+    ("turquoise", 0x40e0d0),
+    ("violet", 0xee82ee),
+    ("wheat", 0xf5deb3),
+    ("white", 0xffffff),
+    ("whitesmoke", 0xf5f5f5),
+    ("yellow", 0xffff00),
+    ("yellowgreen", 0x9acd32),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(s: &str) -> LightColor {
+        LightColor::from_str(s).unwrap()
+    }
+
+    fn assert_xy(color: LightColor, expected: (f64, f64)) {
+        let (x, y) = color.to_cie_xy();
+        assert!(
+            (x - expected.0).abs() < 1e-4 && (y - expected.1).abs() < 1e-4,
+            "{color:?}: expected {expected:?}, got {:?}",
+            (x, y)
+        );
+    }
+
+    #[test]
+    fn parses_the_supported_formats() {
+        let red = LightColor::Rgb { r: 255, g: 0, b: 0 };
+        for s in ["red", " RED ", "#ff0000", "#FF0000", "#f00"] {
+            assert_eq!(color(s), red, "{s}");
+        }
+        assert_eq!(
+            color("cornflowerblue"),
+            LightColor::Rgb {
+                r: 0x64,
+                g: 0x95,
+                b: 0xed
+            }
+        );
+        assert_eq!(
+            color("hsv(120, 100%, 100%)"),
+            LightColor::Hsv {
+                h: 120.0,
+                s: 1.0,
+                v: 1.0
+            }
+        );
+        assert_eq!(
+            color("hsv(360,50,25)"),
+            LightColor::Hsv {
+                h: 0.0,
+                s: 0.5,
+                v: 0.25
+            }
+        );
+        assert_eq!(color("2700K"), LightColor::Temperature { kelvin: 2_700 });
+        assert_eq!(color("2700 k"), LightColor::Temperature { kelvin: 2_700 });
+        assert_eq!(color("40000K"), LightColor::Temperature { kelvin: 40_000 });
+    }
+
+    #[test]
+    fn rejects_invalid_colors() {
+        for (s, expected) in [
+            ("#12345", "Invalid hex color: #12345"),
+            ("#ggg", "Invalid hex color: #ggg"),
+            ("#", "Invalid hex color: #"),
+            ("hsv(361, 0%, 0%)", "Invalid HSV color: hsv(361, 0%, 0%)"),
+            ("hsv(0, 101%, 0%)", "Invalid HSV color: hsv(0, 101%, 0%)"),
+            ("hsv(0, 0%)", "Invalid HSV color: hsv(0, 0%)"),
+            ("hsv(a, 0%, 0%)", "Invalid HSV color: hsv(a, 0%, 0%)"),
+            (
+                "40001K",
+                "Color temperature must be between 1000K and 40000K",
+            ),
+            ("999K", "Color temperature must be between 1000K and 40000K"),
+            ("70000K", "Invalid color temperature: 70000K"),
+            ("2700.5K", "Invalid color temperature: 2700.5K"),
+            ("k", "Color not supported: k"),
+            ("blurple", "Color not supported: blurple"),
+            ("", "Color not supported: "),
+        ] {
+            assert_eq!(LightColor::from_str(s), Err(expected.to_string()), "{s}");
+        }
+    }
+
+    #[test]
+    fn converts_to_hue_and_saturation() {
+        assert_eq!(color("red").to_hue_saturation(), (0, 254));
+        assert_eq!(color("#f00").to_hue_saturation(), (0, 254));
+        assert_eq!(color("hsv(120,100%,100%)").to_hue_saturation(), (85, 254));
+        assert_eq!(color("blue").to_hue_saturation(), (169, 254));
+        assert_eq!(color("white").to_hue_saturation(), (0, 0));
+        // approximated as rgb(255, 167, 87)
+        assert_eq!(color("2700K").to_rgb(), (255, 167, 87));
+        assert_eq!(color("2700K").to_hue_saturation(), (20, 167));
+    }
+
+    #[test]
+    fn converts_to_cie_xy() {
+        // the sRGB primaries
+        assert_xy(color("red"), (0.6401, 0.3300));
+        assert_xy(color("#f00"), (0.6401, 0.3300));
+        assert_xy(color("hsv(120,100%,100%)"), (0.3, 0.6));
+        assert_xy(color("white"), (0.3127, 0.3290));
+        // black has no chromaticity
+        assert_xy(color("black"), (0.3127, 0.3290));
+        assert_xy(color("2700K"), (0.4656, 0.4066));
+    }
+
+    #[test]
+    fn converts_color_temperatures_to_mireds() {
+        assert_eq!(color("2700K").to_mireds(), Some(370));
+        assert_eq!(color("1000K").to_mireds(), Some(1_000));
+        assert_eq!(color("40000K").to_mireds(), Some(25));
+        assert_eq!(color("red").to_mireds(), None);
+        assert_eq!(color("hsv(120,100%,100%)").to_mireds(), None);
     }
 }
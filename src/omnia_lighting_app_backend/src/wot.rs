@@ -1,12 +1,1145 @@
-use candid::CandidType;
+use candid::{CandidType, Nat};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
 
+use crate::{
+    memory::impl_cbor_storable,
+    rdf::{expand_iri, GenericError},
+    topology::Zones,
+    utils::log,
+};
+
+/// A device of an environment, as described in the RDF graph.
 #[derive(Default, Clone, Debug, Serialize, Deserialize, CandidType)]
-pub struct DeviceHeaders {
+pub struct Device {
+    /// The headers needed to send requests to the device.
     pub headers: BTreeMap<String, String>,
+    /// The commands the device supports, as advertised by its action affordances in the RDF graph.
+    #[serde(default)]
+    pub capabilities: BTreeSet<Capability>,
+    /// The label of the device.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The space of the building the device is in.
+    #[serde(default)]
+    pub room: Option<Room>,
+    /// The IRI of the class of the device, more specific than `saref:Device`.
+    ///
+    /// Example: `https://saref.etsi.org/core/LightingDevice`.
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub manufacturer: Option<String>,
+}
+
+/// A space of a building, as defined by the [Building Topology Ontology](https://w3id.org/bot).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, CandidType)]
+pub struct Room {
+    /// The IRI of the `bot:Space`.
+    pub iri: String,
+    /// The label of the space.
+    pub name: Option<String>,
+}
+
+/// A kind of command a device can receive.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Serialize, Deserialize,
+)]
+pub enum Capability {
+    /// Turning the device on and off.
+    ///
+    /// A `saref:ToggleCommand` doesn't provide it, since it can't tell whether the device ends up on or off.
+    // the cached devices were stored with the previous name
+    #[serde(alias = "Toggle")]
+    Power,
+    /// Setting the level, like the brightness of a light.
+    SetLevel,
+    /// Setting the color of a light.
+    SetColor,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 3] = [
+        Capability::Power,
+        Capability::SetLevel,
+        Capability::SetColor,
+    ];
+
+    /// Returns the IRIs of the command classes that provide the capability.
+    ///
+    /// They're also the semantic types of the actions that [crate::light::LightCommand] looks for in the Thing Descriptions.
+    pub const fn command_iris(&self) -> &'static [&'static str] {
+        match self {
+            Capability::Power => &[
+                "https://saref.etsi.org/core/OnOffCommand",
+                "http://rdf.omnia-iot.com#SetPowerCommand",
+            ],
+            Capability::SetLevel => &[
+                "https://saref.etsi.org/core/SetLevelCommand",
+                "http://rdf.omnia-iot.com#SetBrightnessCommand",
+            ],
+            Capability::SetColor => &["http://rdf.omnia-iot.com#SetColorCommand"],
+        }
+    }
+
+    pub fn from_command_iri(iri: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|capability| capability.command_iris().contains(&iri))
+    }
 }
 
+impl_cbor_storable!(Device, ThingDescription);
+
 pub type DeviceUrl = String;
 
-pub type WotDevices = BTreeMap<DeviceUrl, DeviceHeaders>;
+pub type WotDevices = BTreeMap<DeviceUrl, Device>;
+
+pub type EnvironmentUid = String;
+
+/// The devices of an environment, as fetched from the Omnia backend.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EnvironmentDevices {
+    pub devices: WotDevices,
+    /// When the devices have been fetched (in nanoseconds).
+    pub fetched_at: u64,
+    /// The zones of the buildings the devices are in.
+    #[serde(default)]
+    pub zones: Zones,
+}
+
+impl_cbor_storable!(EnvironmentDevices);
+
+pub type ThingDescriptions = BTreeMap<DeviceUrl, ThingDescription>;
+
+/// The parameters used to build the body of an action request.
+///
+/// Keys are matched against the name, the title or the semantic type of the input schema fields.
+pub type ActionParams = BTreeMap<String, Value>;
+
+/// The maximum size of a Thing Description that we accept from a device (64KB).
+const THING_DESCRIPTION_MAX_BYTES: u64 = 65_536;
+
+/// The default content type of a form, as defined by the WoT TD specification.
+const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+/// The operation type used to invoke an action.
+const INVOKE_ACTION_OP: &str = "invokeaction";
+
+/// Some TD fields can be either a single string or an array of strings.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    pub fn contains(&self, value: &str) -> bool {
+        match self {
+            OneOrMany::One(v) => v == value,
+            OneOrMany::Many(values) => values.iter().any(|v| v == value),
+        }
+    }
+
+    /// Returns true if one of the values is the given IRI, either in full or prefixed, see [expand_iri].
+    pub fn contains_iri(&self, iri: &str) -> bool {
+        match self {
+            OneOrMany::One(v) => expand_iri(v) == iri,
+            OneOrMany::Many(values) => values.iter().any(|v| expand_iri(v) == iri),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataType {
+    Object,
+    Array,
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Null,
+}
+
+/// A WoT Data Schema, used to describe the payloads of properties, actions and events.
+///
+/// See <https://www.w3.org/TR/wot-thing-description11/#dataschema>.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataSchema {
+    #[serde(rename = "@type", default, skip_serializing_if = "Option::is_none")]
+    pub semantic_type: Option<OneOrMany>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub data_type: Option<DataType>,
+    #[serde(rename = "const", default, skip_serializing_if = "Option::is_none")]
+    pub const_value: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<Value>,
+    #[serde(rename = "enum", default, skip_serializing_if = "Option::is_none")]
+    pub enumeration: Option<Vec<Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub write_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maximum: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_length: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub items: Option<Box<DataSchema>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_items: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<u32>,
+    #[serde(default)]
+    pub properties: BTreeMap<String, DataSchema>,
+    #[serde(default)]
+    pub required: Vec<String>,
+}
+
+/// A WoT Form, describing how to perform an operation on an affordance.
+///
+/// See <https://www.w3.org/TR/wot-thing-description11/#form>.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Form {
+    pub href: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op: Option<OneOrMany>,
+    #[serde(
+        rename = "htv:methodName",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub method_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subprotocol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security: Option<OneOrMany>,
+}
+
+/// See <https://www.w3.org/TR/wot-thing-description11/#propertyaffordance>.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PropertyAffordance {
+    #[serde(flatten)]
+    pub schema: DataSchema,
+    #[serde(default)]
+    pub observable: bool,
+    #[serde(default)]
+    pub forms: Vec<Form>,
+}
+
+/// See <https://www.w3.org/TR/wot-thing-description11/#actionaffordance>.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActionAffordance {
+    #[serde(rename = "@type", default, skip_serializing_if = "Option::is_none")]
+    pub semantic_type: Option<OneOrMany>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<DataSchema>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<DataSchema>,
+    #[serde(default)]
+    pub safe: bool,
+    #[serde(default)]
+    pub idempotent: bool,
+    #[serde(default)]
+    pub forms: Vec<Form>,
+}
+
+/// See <https://www.w3.org/TR/wot-thing-description11/#eventaffordance>.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EventAffordance {
+    #[serde(rename = "@type", default, skip_serializing_if = "Option::is_none")]
+    pub semantic_type: Option<OneOrMany>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<DataSchema>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subscription: Option<DataSchema>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cancellation: Option<DataSchema>,
+    #[serde(default)]
+    pub forms: Vec<Form>,
+}
+
+/// See <https://www.w3.org/TR/wot-thing-description11/#securityscheme>.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SecurityScheme {
+    pub scheme: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(rename = "in", default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// A W3C WoT Thing Description.
+///
+/// Only the fields that the canister needs to interact with the device are deserialized.
+/// See <https://www.w3.org/TR/wot-thing-description11/#thing>.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThingDescription {
+    #[serde(rename = "@type", default, skip_serializing_if = "Option::is_none")]
+    pub semantic_type: Option<OneOrMany>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    #[serde(default)]
+    pub properties: BTreeMap<String, PropertyAffordance>,
+    #[serde(default)]
+    pub actions: BTreeMap<String, ActionAffordance>,
+    #[serde(default)]
+    pub events: BTreeMap<String, EventAffordance>,
+    #[serde(default)]
+    pub forms: Vec<Form>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security: Option<OneOrMany>,
+    #[serde(default)]
+    pub security_definitions: BTreeMap<String, SecurityScheme>,
+}
+
+/// An action affordance resolved to the concrete HTTP request that invokes it.
+#[derive(Clone, Debug)]
+pub struct ResolvedAction {
+    pub href: String,
+    pub method: HttpMethod,
+    pub content_type: String,
+    pub input: Option<DataSchema>,
+}
+
+impl ThingDescription {
+    /// Returns the name of the first action annotated with the given semantic type,
+    /// whether the TD writes it as a full IRI or with one of the prefixes of [crate::rdf].
+    pub fn find_action_by_type(&self, semantic_type: &str) -> Option<&String> {
+        let semantic_type = expand_iri(semantic_type);
+        self.actions
+            .iter()
+            .find(|(_, action)| match &action.semantic_type {
+                Some(t) => t.contains_iri(&semantic_type),
+                None => false,
+            })
+            .map(|(name, _)| name)
+    }
+
+    /// Resolves the action with the given name to its href, method and content type.
+    ///
+    /// Relative hrefs are resolved against the TD `base` if present, otherwise against the device URL,
+    /// as defined by RFC 3986.
+    pub fn resolve_action(
+        &self,
+        name: &str,
+        device_url: &DeviceUrl,
+    ) -> Result<ResolvedAction, GenericError> {
+        let action = self
+            .actions
+            .get(name)
+            .ok_or_else(|| format!("Action {name} not found in Thing Description"))?;
+
+        // forms without the op field default to invokeaction for action affordances
+        let form = action
+            .forms
+            .iter()
+            .find(|f| match &f.op {
+                Some(op) => op.contains(INVOKE_ACTION_OP),
+                None => true,
+            })
+            .ok_or_else(|| format!("Action {name} has no form to invoke it"))?;
+
+        let method = match form.method_name.as_deref() {
+            // POST is the default method for invokeaction in the HTTP binding
+            None | Some("POST") => HttpMethod::POST,
+            Some("GET") => HttpMethod::GET,
+            Some("HEAD") => HttpMethod::HEAD,
+            Some(m) => {
+                return Err(format!(
+                    "HTTP method {m} is not supported by HTTPS outcalls"
+                ))
+            }
+        };
+
+        let base = self.base.as_ref().unwrap_or(device_url);
+
+        Ok(ResolvedAction {
+            href: resolve_href(base, &form.href),
+            method,
+            content_type: form
+                .content_type
+                .clone()
+                .unwrap_or_else(|| String::from(DEFAULT_CONTENT_TYPE)),
+            input: action.input.clone(),
+        })
+    }
+}
+
+impl ResolvedAction {
+    /// Builds the request body from the action input schema and the given parameters.
+    ///
+    /// Fields that aren't provided by the parameters are filled with their `const` or `default` values,
+    /// then the resulting body is validated against the schema.
+    pub fn build_body(&self, params: &ActionParams) -> Result<Vec<u8>, GenericError> {
+        if !self.content_type.contains("json") {
+            return Err(format!(
+                "Content type {} is not supported",
+                self.content_type
+            ));
+        }
+
+        let body = match &self.input {
+            Some(schema) => {
+                let value = schema.build_value(None, params, "input")?.ok_or_else(|| {
+                    String::from("Invalid input: the parameters don't match the action input")
+                })?;
+                schema
+                    .validate(&value, "input")
+                    .map_err(|e| format!("Invalid input: {e}"))?;
+                value
+            }
+            // actions without an input schema receive the parameters as they are
+            None => serde_json::to_value(params).map_err(|e| e.to_string())?,
+        };
+
+        serde_json::to_vec(&body).map_err(|e| e.to_string())
+    }
+}
+
+impl DataSchema {
+    /// Returns the parameter matching the schema, looking for its name, title or semantic type.
+    fn find_param<'a>(&self, name: Option<&str>, params: &'a ActionParams) -> Option<&'a Value> {
+        let mut keys: Vec<&str> = vec![];
+        keys.extend(name);
+        keys.extend(self.title.as_deref());
+        match &self.semantic_type {
+            Some(OneOrMany::One(t)) => keys.push(t),
+            Some(OneOrMany::Many(types)) => keys.extend(types.iter().map(|t| t.as_str())),
+            None => {}
+        };
+
+        keys.into_iter().find_map(|k| params.get(k))
+    }
+
+    fn is_object(&self) -> bool {
+        self.data_type == Some(DataType::Object)
+            || (self.data_type.is_none() && !self.properties.is_empty())
+    }
+
+    /// Builds the value for this schema, returning `None` if there's nothing to set.
+    fn build_value(
+        &self,
+        name: Option<&str>,
+        params: &ActionParams,
+        path: &str,
+    ) -> Result<Option<Value>, GenericError> {
+        if let Some(value) = &self.const_value {
+            return Ok(Some(value.clone()));
+        }
+
+        if let Some(value) = self.find_param(name, params) {
+            return Ok(Some(value.clone()));
+        }
+
+        if self.is_object() {
+            let mut object = serde_json::Map::new();
+            for (property_name, property) in &self.properties {
+                let property_path = format!("{path}.{property_name}");
+                match property.build_value(Some(property_name), params, &property_path)? {
+                    Some(value) => {
+                        object.insert(property_name.clone(), value);
+                    }
+                    None if self.required.contains(property_name) => {
+                        return Err(format!("Missing required field {property_path}"));
+                    }
+                    None => {}
+                }
+            }
+
+            if !object.is_empty() {
+                return Ok(Some(Value::Object(object)));
+            }
+        }
+
+        Ok(self.default.clone())
+    }
+
+    /// Validates the value against the schema.
+    pub fn validate(&self, value: &Value, path: &str) -> Result<(), GenericError> {
+        if let Some(const_value) = &self.const_value {
+            if value != const_value {
+                return Err(format!("{path}: expected {const_value}, got {value}"));
+            }
+        }
+
+        if let Some(enumeration) = &self.enumeration {
+            if !enumeration.contains(value) {
+                return Err(format!(
+                    "{path}: {value} is not one of {}",
+                    Value::Array(enumeration.clone())
+                ));
+            }
+        }
+
+        if let Some(data_type) = &self.data_type {
+            let valid_type = match data_type {
+                DataType::Object => value.is_object(),
+                DataType::Array => value.is_array(),
+                DataType::String => value.is_string(),
+                DataType::Number => value.is_number(),
+                DataType::Integer => {
+                    value.is_i64()
+                        || value.is_u64()
+                        || value.as_f64().map(|n| n.fract() == 0.0).unwrap_or(false)
+                }
+                DataType::Boolean => value.is_boolean(),
+                DataType::Null => value.is_null(),
+            };
+
+            if !valid_type {
+                return Err(format!("{path}: expected {data_type:?}, got {value}"));
+            }
+        }
+
+        if let Some(n) = value.as_f64() {
+            if let Some(minimum) = self.minimum {
+                if n < minimum {
+                    return Err(format!("{path}: {n} is less than minimum {minimum}"));
+                }
+            }
+            if let Some(maximum) = self.maximum {
+                if n > maximum {
+                    return Err(format!("{path}: {n} is greater than maximum {maximum}"));
+                }
+            }
+        }
+
+        if let Some(s) = value.as_str() {
+            let length = s.chars().count() as u32;
+            if let Some(min_length) = self.min_length {
+                if length < min_length {
+                    return Err(format!("{path}: shorter than {min_length} characters"));
+                }
+            }
+            if let Some(max_length) = self.max_length {
+                if length > max_length {
+                    return Err(format!("{path}: longer than {max_length} characters"));
+                }
+            }
+        }
+
+        if let Some(array) = value.as_array() {
+            let length = array.len() as u32;
+            if let Some(min_items) = self.min_items {
+                if length < min_items {
+                    return Err(format!("{path}: fewer than {min_items} items"));
+                }
+            }
+            if let Some(max_items) = self.max_items {
+                if length > max_items {
+                    return Err(format!("{path}: more than {max_items} items"));
+                }
+            }
+            if let Some(items) = &self.items {
+                for (i, item) in array.iter().enumerate() {
+                    items.validate(item, &format!("{path}[{i}]"))?;
+                }
+            }
+        }
+
+        if let Some(object) = value.as_object() {
+            for required in &self.required {
+                if !object.contains_key(required) {
+                    return Err(format!("{path}: missing required field {required}"));
+                }
+            }
+            for (property_name, property) in &self.properties {
+                if let Some(property_value) = object.get(property_name) {
+                    property.validate(property_value, &format!("{path}.{property_name}"))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The components of a URI reference, see <https://www.rfc-editor.org/rfc/rfc3986#section-3>.
+struct UriReference<'a> {
+    scheme: Option<&'a str>,
+    authority: Option<&'a str>,
+    path: &'a str,
+    query: Option<&'a str>,
+    fragment: Option<&'a str>,
+}
+
+impl<'a> UriReference<'a> {
+    fn parse(reference: &'a str) -> Self {
+        let (rest, fragment) = match reference.split_once('#') {
+            Some((rest, fragment)) => (rest, Some(fragment)),
+            None => (reference, None),
+        };
+        let (rest, query) = match rest.split_once('?') {
+            Some((rest, query)) => (rest, Some(query)),
+            None => (rest, None),
+        };
+
+        // the scheme is only made of these characters and comes before any slash
+        let (scheme, rest) = match rest.split_once(':') {
+            Some((scheme, rest))
+                if scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                    && scheme
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) =>
+            {
+                (Some(scheme), rest)
+            }
+            _ => (None, rest),
+        };
+
+        let (authority, path) = match rest.strip_prefix("//") {
+            Some(rest) => {
+                let path_start = rest.find('/').unwrap_or(rest.len());
+                (Some(&rest[..path_start]), &rest[path_start..])
+            }
+            None => (None, rest),
+        };
+
+        Self {
+            scheme,
+            authority,
+            path,
+            query,
+            fragment,
+        }
+    }
+}
+
+impl std::fmt::Display for UriReference<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(scheme) = self.scheme {
+            write!(f, "{scheme}:")?;
+        }
+        if let Some(authority) = self.authority {
+            write!(f, "//{authority}")?;
+        }
+        write!(f, "{}", self.path)?;
+        if let Some(query) = self.query {
+            write!(f, "?{query}")?;
+        }
+        if let Some(fragment) = self.fragment {
+            write!(f, "#{fragment}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves an href against a base URL, as defined by
+/// [RFC 3986](https://www.rfc-editor.org/rfc/rfc3986#section-5.2).
+///
+/// Example: `/actions/toggle` resolved against `https://gw/things/lamp` is `https://gw/actions/toggle`,
+/// while `lamp/actions/toggle` resolved against `https://gw/things/` is `https://gw/things/lamp/actions/toggle`.
+fn resolve_href(base: &str, href: &str) -> String {
+    let base = UriReference::parse(base);
+    let reference = UriReference::parse(href);
+
+    if reference.scheme.is_some() || reference.authority.is_some() {
+        return UriReference {
+            scheme: reference.scheme.or(base.scheme),
+            path: &remove_dot_segments(reference.path),
+            ..reference
+        }
+        .to_string();
+    }
+
+    let (path, query) = if reference.path.is_empty() {
+        (base.path.to_string(), reference.query.or(base.query))
+    } else if reference.path.starts_with('/') {
+        (remove_dot_segments(reference.path), reference.query)
+    } else {
+        (
+            remove_dot_segments(&merge_paths(&base, reference.path)),
+            reference.query,
+        )
+    };
+
+    UriReference {
+        scheme: base.scheme,
+        authority: base.authority,
+        path: &path,
+        query,
+        fragment: reference.fragment,
+    }
+    .to_string()
+}
+
+/// Merges a relative path with the path of the base, see <https://www.rfc-editor.org/rfc/rfc3986#section-5.2.3>.
+fn merge_paths(base: &UriReference, path: &str) -> String {
+    if base.authority.is_some() && base.path.is_empty() {
+        return format!("/{path}");
+    }
+
+    match base.path.rfind('/') {
+        Some(i) => format!("{}{path}", &base.path[..=i]),
+        None => path.to_string(),
+    }
+}
+
+/// Interprets the `.` and `..` segments of a path, see <https://www.rfc-editor.org/rfc/rfc3986#section-5.2.4>.
+fn remove_dot_segments(path: &str) -> String {
+    let mut input = path;
+    let mut output = String::new();
+    let remove_last_segment = |output: &mut String| output.truncate(output.rfind('/').unwrap_or(0));
+
+    while !input.is_empty() {
+        if let Some(rest) = input
+            .strip_prefix("../")
+            .or_else(|| input.strip_prefix("./"))
+        {
+            input = rest;
+        } else if input.starts_with("/./") {
+            input = &input[2..];
+        } else if input == "/." {
+            input = "/";
+        } else if input.starts_with("/../") {
+            input = &input[3..];
+            remove_last_segment(&mut output);
+        } else if input == "/.." {
+            input = "/";
+            remove_last_segment(&mut output);
+        } else if input == "." || input == ".." {
+            input = "";
+        } else {
+            // moves the first segment, with its leading slash, to the output
+            let start = if input.starts_with('/') { 1 } else { 0 };
+            let end = input[start..].find('/').map_or(input.len(), |i| i + start);
+            output.push_str(&input[..end]);
+            input = &input[end..];
+        }
+    }
+
+    output
+}
+
+/// Fetches the Thing Description exposed by the device at its URL.
+pub async fn fetch_thing_description(
+    device_url: &DeviceUrl,
+    headers: Vec<HttpHeader>,
+) -> Result<ThingDescription, GenericError> {
+    let request = CanisterHttpRequestArgument {
+        url: device_url.clone(),
+        method: HttpMethod::GET,
+        body: None,
+        max_response_bytes: Some(THING_DESCRIPTION_MAX_BYTES),
+        transform: Some(TransformContext::from_name(
+            String::from("transform_thing_description_response"),
+            vec![],
+        )),
+        headers,
+    };
+
+    // using 1B cycles, since the TD can be quite big (unused cycles are refunded)
+    let (response,) = http_request(request, 1_000_000_000)
+        .await
+        .map_err(|(r, m)| format!("RejectionCode: {r:?}, Error: {m}"))?;
+
+    // needed just to avoid clippy warnings
+    #[allow(clippy::cmp_owned)]
+    if response.status != Nat::from(200) {
+        return Err(format!(
+            "Failed to fetch Thing Description, HTTP status: {}",
+            response.status
+        ));
+    }
+
+    let td = serde_json::from_slice::<ThingDescription>(&response.body)
+        .map_err(|e| format!("Failed to parse Thing Description: {e}"))?;
+
+    log(format!(
+        "Fetched Thing Description for {device_url}: {} actions",
+        td.actions.len()
+    ));
+
+    Ok(td)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn resolved_action(content_type: &str, input: Option<Value>) -> ResolvedAction {
+        ResolvedAction {
+            href: String::from("https://gw/things/lamp/actions/set"),
+            method: HttpMethod::POST,
+            content_type: content_type.to_string(),
+            input: input.map(|input| serde_json::from_value(input).unwrap()),
+        }
+    }
+
+    fn params(params: Value) -> ActionParams {
+        serde_json::from_value(params).unwrap()
+    }
+
+    fn build_body(input: Value, action_params: Value) -> Result<Value, GenericError> {
+        resolved_action(DEFAULT_CONTENT_TYPE, Some(input))
+            .build_body(&params(action_params))
+            .map(|body| serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn resolves_hrefs_against_the_base() {
+        // the examples of RFC 3986, section 5.4
+        let base = "http://a/b/c/d;p?q";
+        for (href, expected) in [
+            ("g:h", "g:h"),
+            ("g", "http://a/b/c/g"),
+            ("./g", "http://a/b/c/g"),
+            ("g/", "http://a/b/c/g/"),
+            ("/g", "http://a/g"),
+            ("//g", "http://g"),
+            ("?y", "http://a/b/c/d;p?y"),
+            ("g?y", "http://a/b/c/g?y"),
+            ("#s", "http://a/b/c/d;p?q#s"),
+            ("g#s", "http://a/b/c/g#s"),
+            ("g?y#s", "http://a/b/c/g?y#s"),
+            (";x", "http://a/b/c/;x"),
+            ("", "http://a/b/c/d;p?q"),
+            (".", "http://a/b/c/"),
+            ("./", "http://a/b/c/"),
+            ("..", "http://a/b/"),
+            ("../", "http://a/b/"),
+            ("../g", "http://a/b/g"),
+            ("../..", "http://a/"),
+            ("../../", "http://a/"),
+            ("../../g", "http://a/g"),
+            ("../../../g", "http://a/g"),
+            ("/./g", "http://a/g"),
+            ("/../g", "http://a/g"),
+            ("g.", "http://a/b/c/g."),
+            ("..g", "http://a/b/c/..g"),
+            ("./../g", "http://a/b/g"),
+            ("./g/.", "http://a/b/c/g/"),
+            ("g/./h", "http://a/b/c/g/h"),
+            ("g/../h", "http://a/b/c/h"),
+            ("g;x=1/../y", "http://a/b/c/y"),
+            ("g?y/./x", "http://a/b/c/g?y/./x"),
+            ("g#s/../x", "http://a/b/c/g#s/../x"),
+            ("http:g", "http:g"),
+        ] {
+            assert_eq!(resolve_href(base, href), expected, "{href}");
+        }
+
+        for (base, href, expected) in [
+            (
+                "https://gw/things/lamp",
+                "/actions/x",
+                "https://gw/actions/x",
+            ),
+            (
+                "https://gw/things/lamp",
+                "actions/x",
+                "https://gw/things/actions/x",
+            ),
+            (
+                "https://gw/things/lamp/",
+                "actions/x",
+                "https://gw/things/lamp/actions/x",
+            ),
+            (
+                "https://gw/things/lamp/",
+                "../hub/x",
+                "https://gw/things/hub/x",
+            ),
+            (
+                "https://gw/things/lamp?key=1",
+                "?key=2",
+                "https://gw/things/lamp?key=2",
+            ),
+            ("https://gw", "actions/x", "https://gw/actions/x"),
+            (
+                "https://gw:8443/things/",
+                "https://other/x",
+                "https://other/x",
+            ),
+        ] {
+            assert_eq!(resolve_href(base, href), expected, "{base} {href}");
+        }
+    }
+
+    fn thing_description(base: Option<&str>) -> ThingDescription {
+        serde_json::from_value(json!({
+            "title": "Lamp",
+            "base": base,
+            "actions": {
+                "toggle": { "forms": [
+                    { "href": "https://other/toggle", "op": "readproperty" },
+                    { "href": "actions/toggle", "op": ["queryaction", "invokeaction"] },
+                ] },
+                "fade": { "forms": [
+                    { "href": "/fade", "htv:methodName": "GET", "contentType": "text/plain" },
+                ] },
+                "reset": { "forms": [{ "href": "reset", "htv:methodName": "PUT" }] },
+                "status": { "forms": [{ "href": "status", "op": "queryaction" }] },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn resolves_actions_to_their_requests() {
+        let device_url = String::from("https://gw/things/lamp/");
+
+        let td = thing_description(None);
+        let toggle = td.resolve_action("toggle", &device_url).unwrap();
+        assert_eq!(toggle.href, "https://gw/things/lamp/actions/toggle");
+        assert_eq!(toggle.method, HttpMethod::POST);
+        assert_eq!(toggle.content_type, DEFAULT_CONTENT_TYPE);
+
+        let fade = td.resolve_action("fade", &device_url).unwrap();
+        assert_eq!(fade.href, "https://gw/fade");
+        assert_eq!(fade.method, HttpMethod::GET);
+        assert_eq!(fade.content_type, "text/plain");
+
+        // the TD base takes precedence over the device URL
+        let td = thing_description(Some("https://hub/things/lamp"));
+        let toggle = td.resolve_action("toggle", &device_url).unwrap();
+        assert_eq!(toggle.href, "https://hub/things/actions/toggle");
+
+        for (name, expected) in [
+            (
+                "reset",
+                "HTTP method PUT is not supported by HTTPS outcalls",
+            ),
+            ("status", "Action status has no form to invoke it"),
+            ("missing", "Action missing not found in Thing Description"),
+        ] {
+            assert_eq!(td.resolve_action(name, &device_url).unwrap_err(), expected);
+        }
+    }
+
+    #[test]
+    fn builds_the_body_from_the_input_schema() {
+        let cases = [
+            (
+                "fills the const fields",
+                json!({ "properties": { "on": { "type": "boolean", "const": true }, "level": { "type": "integer" } } }),
+                json!({ "level": 50, "on": false }),
+                json!({ "on": true, "level": 50 }),
+            ),
+            (
+                "fills the defaults of the missing fields",
+                json!({ "properties": { "level": { "type": "integer" }, "transition": { "type": "integer", "default": 0 } } }),
+                json!({ "level": 50 }),
+                json!({ "level": 50, "transition": 0 }),
+            ),
+            (
+                "matches the fields by title or semantic type",
+                json!({ "properties": {
+                    "bri": { "@type": "saref:Brightness", "type": "number" },
+                    "ct": { "title": "Color temperature", "type": "integer" },
+                } }),
+                json!({ "saref:Brightness": 0.5, "Color temperature": 370 }),
+                json!({ "bri": 0.5, "ct": 370 }),
+            ),
+            (
+                "accepts integers written as floats",
+                json!({ "properties": { "level": { "type": "integer" } } }),
+                json!({ "level": 2.0 }),
+                json!({ "level": 2.0 }),
+            ),
+            (
+                "builds nested objects and arrays",
+                json!({ "type": "object", "properties": {
+                    "color": {
+                        "type": "object",
+                        "properties": { "x": { "type": "number" }, "y": { "type": "number" } },
+                        "required": ["x", "y"],
+                    },
+                    "steps": { "type": "array", "items": { "type": "integer", "minimum": 0 }, "maxItems": 2 },
+                } }),
+                json!({ "x": 0.3, "y": 0.4, "steps": [1, 2] }),
+                json!({ "color": { "x": 0.3, "y": 0.4 }, "steps": [1, 2] }),
+            ),
+            (
+                "sends a scalar input as it is",
+                json!({ "type": "number", "minimum": 0, "maximum": 1, "title": "level" }),
+                json!({ "level": 0.5 }),
+                json!(0.5),
+            ),
+        ];
+
+        for (name, input, action_params, expected) in cases {
+            assert_eq!(build_body(input, action_params), Ok(expected), "{name}");
+        }
+    }
+
+    #[test]
+    fn rejects_the_parameters_that_dont_match_the_input_schema() {
+        let cases = [
+            (
+                "missing required field",
+                json!({ "properties": { "level": { "type": "integer" } }, "required": ["level"] }),
+                json!({}),
+                "Missing required field input.level",
+            ),
+            (
+                "missing nested required field",
+                json!({ "properties": { "color": {
+                    "properties": { "x": { "type": "number" }, "y": { "type": "number" } },
+                    "required": ["x", "y"],
+                } } }),
+                json!({ "x": 0.3 }),
+                "Missing required field input.color.y",
+            ),
+            (
+                "no matching parameter",
+                json!({ "properties": { "level": { "type": "integer" } } }),
+                json!({ "brightness": 50 }),
+                "Invalid input: the parameters don't match the action input",
+            ),
+            (
+                "value not in the enum",
+                json!({ "properties": { "mode": { "type": "string", "enum": ["day", "night"] } } }),
+                json!({ "mode": "party" }),
+                r#"Invalid input: input.mode: "party" is not one of ["day","night"]"#,
+            ),
+            (
+                "below the minimum",
+                json!({ "properties": { "level": { "type": "integer", "minimum": 0, "maximum": 100 } } }),
+                json!({ "level": -1 }),
+                "Invalid input: input.level: -1 is less than minimum 0",
+            ),
+            (
+                "above the maximum",
+                json!({ "properties": { "level": { "type": "integer", "minimum": 0, "maximum": 100 } } }),
+                json!({ "level": 101 }),
+                "Invalid input: input.level: 101 is greater than maximum 100",
+            ),
+            (
+                "number for an integer",
+                json!({ "properties": { "level": { "type": "integer" } } }),
+                json!({ "level": 1.5 }),
+                "Invalid input: input.level: expected Integer, got 1.5",
+            ),
+            (
+                "string for a number",
+                json!({ "properties": { "level": { "type": "number" } } }),
+                json!({ "level": "1" }),
+                r#"Invalid input: input.level: expected Number, got "1""#,
+            ),
+            (
+                "invalid array item",
+                json!({ "properties": { "steps": { "type": "array", "items": { "type": "integer", "minimum": 0 } } } }),
+                json!({ "steps": [1, -1] }),
+                "Invalid input: input.steps[1]: -1 is less than minimum 0",
+            ),
+            (
+                "too many array items",
+                json!({ "properties": { "steps": { "type": "array", "maxItems": 2 } } }),
+                json!({ "steps": [1, 2, 3] }),
+                "Invalid input: input.steps: more than 2 items",
+            ),
+        ];
+
+        for (name, input, action_params, expected) in cases {
+            assert_eq!(
+                build_body(input, action_params),
+                Err(expected.to_string()),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn sends_the_parameters_as_they_are_without_an_input_schema() {
+        let body = resolved_action(DEFAULT_CONTENT_TYPE, None)
+            .build_body(&params(json!({ "level": 50 })))
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({ "level": 50 })
+        );
+    }
+
+    #[test]
+    fn rejects_non_json_content_types() {
+        assert_eq!(
+            resolved_action("text/plain", None).build_body(&params(json!({ "level": 50 }))),
+            Err(String::from("Content type text/plain is not supported"))
+        );
+        assert!(resolved_action("application/td+json", None)
+            .build_body(&params(json!({})))
+            .is_ok());
+    }
+
+    #[test]
+    fn validates_values_against_the_schema() {
+        let schema: DataSchema = serde_json::from_value(json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "minLength": 1, "maxLength": 3 },
+                "tags": { "type": "array", "minItems": 1 },
+            },
+            "required": ["name"],
+        }))
+        .unwrap();
+
+        assert_eq!(schema.validate(&json!({ "name": "abc" }), "input"), Ok(()));
+        for (value, expected) in [
+            (json!([]), "input: expected Object, got []"),
+            (json!({}), "input: missing required field name"),
+            (
+                json!({ "name": "" }),
+                "input.name: shorter than 1 characters",
+            ),
+            (
+                json!({ "name": "abcd" }),
+                "input.name: longer than 3 characters",
+            ),
+            (
+                json!({ "name": "a", "tags": [] }),
+                "input.tags: fewer than 1 items",
+            ),
+        ] {
+            assert_eq!(schema.validate(&value, "input"), Err(expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn decodes_the_power_capability_stored_with_its_previous_name() {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer("Toggle", &mut bytes).unwrap();
+
+        let capability: Capability = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(capability, Capability::Power);
+    }
+}
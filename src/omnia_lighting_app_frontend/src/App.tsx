@@ -6,6 +6,7 @@ import ChooseColorModal from './components/ChooseColorModal';
 import LiveStream from './components/LiveStream';
 import { useDevices } from './contexts/DevicesContext';
 import { useCommands } from './contexts/CommandsContext';
+import { useAuth } from './contexts/AuthContext';
 import { getCardColorScheme } from './utils/lightColor';
 import PrincipalDisplay from './components/PrincipalDisplay';
 
@@ -18,15 +19,21 @@ const App = () => {
   const [isEnvInputVisible,] = useState<boolean>(!envUid);
   const { devices, isLoading, fetchDevices, resetDevices, getDeviceName } = useDevices();
   const { lastDevicesCommand } = useCommands();
+  const { isAuthenticated, isLoading: isLoggingIn, login } = useAuth();
 
   const handleListDevicesClick = useCallback(async () => {
+    if (!isAuthenticated) {
+      await login();
+      return;
+    }
+
     if (!envUid) {
       alert("Please enter an environment unique ID");
       return;
     }
 
     await fetchDevices(envUid);
-  }, [envUid, fetchDevices]);
+  }, [envUid, fetchDevices, isAuthenticated, login]);
 
   const handleDeviceClick = useCallback(async (deviceUrl: string) => {
     setSelectedDeviceUrl(deviceUrl);
@@ -80,12 +87,12 @@ const App = () => {
                 </FormControl>
               )}
               <Button
-                isLoading={isLoading}
-                loadingText="Retrieving devices"
+                isLoading={isLoading || isLoggingIn}
+                loadingText={isLoggingIn ? "Logging in" : "Retrieving devices"}
                 onClick={handleListDevicesClick}
               >
                 {
-                  !isLoading ? "List devices" : <Spinner />
+                  !isLoading ? (isAuthenticated ? "List devices" : "Login to list devices") : <Spinner />
                 }
               </Button>
             </VStack>
@@ -121,11 +128,11 @@ const App = () => {
                       lg: 16,
                     }}
                   >
-                    {devices.map(([deviceUrl,]) => (
+                    {devices.map(([deviceUrl, device]) => (
                       <Card
                         key={deviceUrl}
                         align="center"
-                        backgroundColor={getCardColorScheme(lastDevicesCommand[deviceUrl]?.metadata[0]?.light_color)}
+                        backgroundColor={getCardColorScheme(lastDevicesCommand[deviceUrl]?.metadata[0]?.light_color[0])}
                       >
                         <CardHeader>
                           {getDeviceName(deviceUrl)}
@@ -136,10 +143,11 @@ const App = () => {
                           <Stack divider={<StackDivider />}>
                             <Box>
                               <Button
-                                aria-label="Toggle light"
+                                aria-label="Set light color"
                                 leftIcon={<RiLightbulbLine />}
                                 onClick={() => handleDeviceClick(deviceUrl)}
-                                colorScheme={lastDevicesCommand[deviceUrl]?.metadata[0]?.light_color}
+                                isDisabled={!device.capabilities.some((capability) => "SetColor" in capability)}
+                                colorScheme={lastDevicesCommand[deviceUrl]?.metadata[0]?.light_color[0]}
                                 marginBottom={2}
                               >
                                 Set color
@@ -30,7 +30,7 @@ const ChooseColorModal: React.FC<Props> = ({ isOpen, deviceUrl, onClose }) => {
     const [isLoading, setIsLoading] = useState(false);
     const { isAuthenticated, actor, login } = useAuth();
     const [selectedColor, setSelectedColor] = useState<AvailableLightColors | null>('red');
-    const { getDeviceName } = useDevices();
+    const { environmentUid, getDeviceName } = useDevices();
     const modalContentStyle = useBreakpointValue<CSSProperties | undefined>({
         base: {
             position: "absolute",
@@ -60,8 +60,19 @@ const ChooseColorModal: React.FC<Props> = ({ isOpen, deviceUrl, onClose }) => {
 
         try {
             const result = await actor!.schedule_command({
+                environment_uid: environmentUid!,
                 device_url: deviceUrl,
-                light_color: selectedColor,
+                command: {
+                    power: [],
+                    light_color: [selectedColor],
+                    hue: [],
+                    saturation: [],
+                    brightness: [],
+                    color_temperature: [],
+                    transition_time: [],
+                },
+                schedule: [],
+                retry_policy: [],
             });
             setIsLoading(false);
 
@@ -74,7 +85,7 @@ const ChooseColorModal: React.FC<Props> = ({ isOpen, deviceUrl, onClose }) => {
             setIsLoading(false);
             alert(e);
         }
-    }, [deviceUrl, selectedColor, actor, onClose]);
+    }, [deviceUrl, environmentUid, selectedColor, actor, onClose]);
 
     return (
         <Modal
@@ -33,7 +33,7 @@ export const CommandsRow: React.FC<CommandsRowProps> = ({ command, status }) =>
                 </Tag>
             </Td>
             <Td>
-                <Color color={command.metadata[0]?.light_color as AvailableLightColors} />
+                <Color color={command.metadata[0]?.light_color[0] as AvailableLightColors} />
             </Td>
             <Td>
                 {status === 'scheduled'
@@ -86,9 +86,9 @@ const CommandsQueue = () => {
                             </Tr>
                         </Thead>
                         <Tbody>
-                            {scheduledCommands.map(([scheduledTimestamp, command]) => (
+                            {scheduledCommands.map(([commandId, command]) => (
                                 <CommandsRow
-                                    key={`scheduled-${scheduledTimestamp.toString()}`}
+                                    key={`scheduled-${commandId.toString()}`}
                                     command={command}
                                     status='scheduled'
                                 />
@@ -135,9 +135,9 @@ const CommandsQueue = () => {
                                             </Tr>
                                         </Thead>
                                         <Tbody>
-                                            {finishedCommands.map(([executionTimestamp, command]) => (
+                                            {finishedCommands.map(([commandId, command]) => (
                                                 <CommandsRow
-                                                    key={`finished-${executionTimestamp.toString()}`}
+                                                    key={`finished-${commandId.toString()}`}
                                                     command={command}
                                                     status='finished'
                                                 />
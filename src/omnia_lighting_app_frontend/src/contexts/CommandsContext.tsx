@@ -31,7 +31,8 @@ export const CommandsProvider: React.FC<Props> = ({ children }) => {
     const [lastDevicesCommand, setLastDevicesCommand] = useState<LastDevicesCommand>({});
 
     const fetchCommands = useCallback(async () => {
-        const commandsResult = await omnia_lighting_app_backend.get_commands();
+        // the first page of the scheduled commands is enough for the queue
+        const commandsResult = await omnia_lighting_app_backend.get_commands({ cursor: [], limit: [] });
 
         console.log("fetchedCommands", commandsResult);
 
@@ -40,10 +41,10 @@ export const CommandsProvider: React.FC<Props> = ({ children }) => {
         const _runningCommands = commandsResult.running_commands;
         const _finishedCommands = commandsResult.finished_commands;
         // keep in running commands the commands that were executed in the last 15 seconds
-        for (const [ts, cmd] of _finishedCommands) {
-            if (differenceInMilliseconds(new Date(), getDate(ts)) < 15_000) {
-                _runningCommands.push([ts, cmd]);
-                _finishedCommands.splice(_finishedCommands.indexOf([ts, cmd]), 1);
+        for (const [id, cmd] of _finishedCommands) {
+            if (differenceInMilliseconds(new Date(), getDate(cmd.schedule_timestamp)) < 15_000) {
+                _runningCommands.push([id, cmd]);
+                _finishedCommands.splice(_finishedCommands.indexOf([id, cmd]), 1);
             }
         }
 
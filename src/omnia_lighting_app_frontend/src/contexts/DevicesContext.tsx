@@ -1,9 +1,10 @@
-import { Context, createContext, useCallback, useContext, useState } from "react";
+import { Context, createContext, useCallback, useContext, useRef, useState } from "react";
 import { WotDevices } from "../../../declarations/omnia_lighting_app_backend/omnia_lighting_app_backend.did";
-import { omnia_lighting_app_backend } from "../../../declarations/omnia_lighting_app_backend";
+import { useAuth } from "./AuthContext";
 
 export type DevicesContextType = {
     devices: WotDevices | null;
+    environmentUid: string | null;
     isLoading: boolean;
     fetchDevices: (environmentUid: string) => Promise<void>;
     resetDevices: () => void
@@ -18,34 +19,74 @@ type Props = {
 
 export const DevicesProvider: React.FC<Props> = ({ children }) => {
     const [devices, setDevices] = useState<WotDevices | null>(null);
+    const [environmentUid, setEnvironmentUid] = useState<string | null>(null);
     const [isLoading, setIsLoading] = useState(false);
+    // the backend only returns the devices of an environment to its members
+    const { actor } = useAuth();
+    // the environment requested last, so that a slow refresh doesn't show the devices of a previous one
+    const requestedEnvironmentUid = useRef<string | null>(null);
+
+    const showDevices = useCallback((environmentUid: string, devices: WotDevices) => {
+        if (requestedEnvironmentUid.current !== environmentUid) {
+            return;
+        }
+
+        // we reverse the array just to have lights in the right order (from first paired to last paired)
+        setDevices(devices.reverse());
+        setEnvironmentUid(environmentUid);
+    }, []);
 
     const fetchDevices = useCallback(async (environmentUid: string) => {
+        requestedEnvironmentUid.current = environmentUid;
+
         try {
+            if (!actor) {
+                throw new Error("Please log in to list the devices");
+            }
+
             setIsLoading(true);
-            const devicesResult = await omnia_lighting_app_backend.get_devices_in_environment(environmentUid);
+            // the cached devices are served by a query, so they're shown right away even if they're stale
+            const cachedResult = await actor.get_devices_in_environment(environmentUid, []);
+            if ("Ok" in cachedResult) {
+                showDevices(environmentUid, cachedResult.Ok.devices);
+                setIsLoading(false);
+
+                if (!cachedResult.Ok.is_stale) {
+                    return;
+                }
+            }
 
+            // we only wait for the backend to fetch the devices when they've never been fetched
+            const refreshResult = await actor.refresh_environment(environmentUid);
             setIsLoading(false);
 
-            if ("Ok" in devicesResult) {
-                // we reverse the array just to have lights in the right order (from first paired to last paired)
-                setDevices(devicesResult.Ok.reverse());
+            if ("Ok" in refreshResult) {
+                showDevices(environmentUid, refreshResult.Ok.devices);
+            } else if ("Err" in cachedResult) {
+                throw refreshResult.Err;
             } else {
-                throw devicesResult.Err;
+                console.error("Failed to refresh the devices", refreshResult.Err);
             }
         } catch (e) {
             setIsLoading(false);
             alert(e);
         }
-    }, []);
+    }, [actor, showDevices]);
 
     const resetDevices = useCallback(() => {
+        requestedEnvironmentUid.current = null;
         setDevices(null);
+        setEnvironmentUid(null);
     }, []);
 
     const getDeviceName = useCallback((deviceUrl: string) => {
         const deviceIndex = devices?.findIndex((d) => d[0] === deviceUrl);
 
+        const name = deviceIndex !== undefined ? devices?.[deviceIndex]?.[1].name[0] : undefined;
+        if (name) {
+            return name;
+        }
+
         if (deviceIndex !== undefined) {
             return `Light #${deviceIndex + 1}`;
         }
@@ -57,6 +98,7 @@ export const DevicesProvider: React.FC<Props> = ({ children }) => {
         <DevicesContext.Provider
             value={{
                 devices,
+                environmentUid,
                 isLoading,
                 fetchDevices,
                 resetDevices,